@@ -2,13 +2,15 @@ Helpers:log("Loading idle_config.lua")
 
 function LockScreen()
 	Helpers:log("Locking Screen")
-	IdleNotifier:run_once("swaylock")
+	-- swaylock -f forks once the screen is locked, when called from a sleep handler the system
+	-- only suspends after that
+	IdleNotifier:lock("swaylock -f", { timeout = 3 })
 end
 
 function LockHandler()
-	IdleNotifier:run("playerctl -a pause")
-	IdleNotifier:run("nmcli radio wwan off")
 	LockScreen()
+	Media:pause_all()
+	IdleNotifier:run("nmcli radio wwan off")
 end
 
 function UnlockHandler()
@@ -17,18 +19,19 @@ end
 
 function DpmsOn()
 	Helpers:log("Turning screen on")
-	IdleNotifier:run("swaymsg output '*' dpms on")
-	-- IdleNotifier:run("hyprctl dispatch dpms on")
+	Outputs:power_on()
 end
 
 function DpmsOff()
 	Helpers:log("Turning screen off")
-	IdleNotifier:run("swaymsg output '*' dpms off")
-	-- IdleNotifier:run("hyprctl dispatch dpms off")
+	Outputs:power_off()
 end
 
 function HybridSleep()
-	IdleNotifier:run("systemctl suspend")
+	local ok, err = Power:suspend()
+	if not ok then
+		Helpers:log("Failed to suspend: " .. err)
+	end
 end
 
 function ScreenLockBattery(event)
@@ -72,13 +75,13 @@ function ScreenDpmsAC(event)
 	end
 end
 
-DbusHandler:PrepareSleep("LockHandler")
-DbusHandler:LockHandler("LockHandler")
--- DbusHandler:UnlockHandler("UnlockHandler")
-IdleNotifier:get_notification(300,  "ScreenLockBattery")
-IdleNotifier:get_notification(30,  "ScreenDpmsBattery")
-IdleNotifier:get_notification(600,  "SleepBattery")
-IdleNotifier:get_notification(600,  "ScreenLockAC")
-IdleNotifier:get_notification(900,  "ScreenDpmsAC")
+Events:on("prepare_sleep", LockHandler)
+Events:on("lock", LockHandler)
+-- Events:on("unlock", UnlockHandler)
+IdleNotifier:get_notification(300,  ScreenLockBattery)
+IdleNotifier:get_notification(30,  ScreenDpmsBattery)
+IdleNotifier:get_notification(600,  SleepBattery)
+IdleNotifier:get_notification(600,  ScreenLockAC)
+IdleNotifier:get_notification(900,  ScreenDpmsAC)
 
 Helpers:log("Finished loading idle_config.lua")
@@ -2,3 +2,8 @@ pub const APP_NAME: &str = "sleepwatcher-rs";
 pub const CONFIG_FILE_NAME: &str = "idle_config.lua";
 pub const CONFIG_FILE: &str = include_str!("../lua_configs/idle_config.lua");
 pub const TIMEOUT_SEC: u64 = 30;
+pub const LUA_MEMORY_LIMIT_MB: usize = 64;
+pub const LUA_TIMEOUT_MS: u64 = 5000;
+pub const MAX_SLEEP_DELAY_MS: u64 = 5000;
+pub const NIGHT_LIGHT_INTERVAL_SEC: u64 = 60;
+pub const FADE_STEP_MS: u64 = 50;
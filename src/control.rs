@@ -0,0 +1,375 @@
+use super::{
+    types::{Event, Request, Status, TimerInfo},
+    utils,
+};
+use clap::Subcommand;
+use log::debug;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
+use zbus::{
+    dbus_interface, dbus_proxy, fdo,
+    zvariant::{OwnedValue, Value},
+    Connection, ConnectionBuilder, SignalContext,
+};
+
+/// Well-known name the daemon owns on the session bus
+pub const BUS_NAME: &str = "io.github.fishman.SleepWatcher";
+pub const OBJECT_PATH: &str = "/io/github/fishman/SleepWatcher";
+/// Source of inhibits requested over the control interface
+const INHIBIT_SOURCE: &str = "control";
+
+/// Control interface of a running daemon. Every call is forwarded to the request loop.
+#[derive(Debug)]
+struct ControlInterface {
+    tx: mpsc::Sender<Request>,
+}
+
+impl ControlInterface {
+    async fn send(&self, request: Request) -> fdo::Result<()> {
+        self.tx
+            .send(request)
+            .await
+            .map_err(|_| fdo::Error::Failed("The daemon is shutting down".to_string()))
+    }
+
+    async fn query<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<T>) -> Request,
+    ) -> fdo::Result<T> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(request(reply_tx)).await?;
+        reply_rx
+            .await
+            .map_err(|_| fdo::Error::Failed("The request was dropped".to_string()))
+    }
+}
+
+#[dbus_interface(name = "io.github.fishman.SleepWatcher")]
+impl ControlInterface {
+    /// Reloads the config file
+    async fn reload(&self) -> fdo::Result<()> {
+        debug!("Reload requested over D-Bus");
+        self.send(Request::Reset).await
+    }
+
+    /// Inhibits idle for `duration` seconds, or until `Uninhibit` is called if it's 0
+    async fn inhibit(&self, duration: u32, reason: String) -> fdo::Result<()> {
+        debug!("Inhibit for {}s requested over D-Bus: {}", duration, reason);
+        self.send(Request::Inhibit {
+            source: INHIBIT_SOURCE.to_string(),
+            reason,
+            duration: (duration > 0).then(|| Duration::from_secs(duration.into())),
+        })
+        .await
+    }
+
+    async fn uninhibit(&self) -> fdo::Result<()> {
+        debug!("Uninhibit requested over D-Bus");
+        self.send(Request::Uninhibit(INHIBIT_SOURCE.to_string()))
+            .await
+    }
+
+    async fn get_status(&self) -> fdo::Result<HashMap<String, OwnedValue>> {
+        let status = self.query(Request::GetStatus).await?;
+        Ok(status_dict(status))
+    }
+
+    /// Lists the idle notifications of the running config as (id, timeout, idle, idle_hint)
+    async fn list_timers(&self) -> fdo::Result<Vec<(String, u32, bool, bool)>> {
+        let timers = self.query(Request::ListTimers).await?;
+        Ok(timers
+            .into_iter()
+            .map(|timer| {
+                (
+                    timer.id.to_string(),
+                    timer.timeout,
+                    timer.idle,
+                    timer.idle_hint,
+                )
+            })
+            .collect())
+    }
+
+    /// Emits an event to the Lua handlers as if it happened
+    async fn trigger(&self, event: String) -> fdo::Result<()> {
+        let Some(event) = Event::from_name(&event) else {
+            let names: Vec<&str> = Event::TRIGGERABLE.iter().map(Event::name).collect();
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Unknown event {}, expected one of: {}",
+                event,
+                names.join(", ")
+            )));
+        };
+        debug!("Triggering {} over D-Bus", event.name());
+        self.send(Request::Event(event)).await
+    }
+
+    #[dbus_interface(signal)]
+    async fn idled(ctxt: &SignalContext<'_>, id: String, timeout: u32) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn resumed(ctxt: &SignalContext<'_>, id: String, timeout: u32) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn locked(ctxt: &SignalContext<'_>, locked: bool) -> zbus::Result<()>;
+}
+
+/// Converts the status into a dictionary, so fields can be added without breaking clients
+fn status_dict(status: Status) -> HashMap<String, OwnedValue> {
+    let now = Instant::now();
+    let inhibits: Vec<(String, String, u64)> = status
+        .inhibits
+        .into_iter()
+        .map(|(source, inhibit)| {
+            // Seconds left, 0 if the inhibit lasts until it's released
+            let remaining = inhibit
+                .until
+                .map(|until| until.saturating_duration_since(now).as_secs().max(1))
+                .unwrap_or(0);
+            (source, inhibit.reason, remaining)
+        })
+        .collect();
+    let session = status.session.map(|session| session.id).unwrap_or_default();
+
+    HashMap::from([
+        (
+            "on_battery".to_string(),
+            Value::from(status.on_battery).into(),
+        ),
+        ("locked".to_string(), Value::from(status.locked).into()),
+        (
+            "config_error".to_string(),
+            Value::from(status.config_error.unwrap_or_default()).into(),
+        ),
+        ("session".to_string(), Value::from(session).into()),
+        ("inhibits".to_string(), Value::from(inhibits).into()),
+    ])
+}
+
+/// Claims the well-known name on the session bus and serves the control interface
+pub async fn serve(tx: mpsc::Sender<Request>) -> anyhow::Result<Connection> {
+    let conn = ConnectionBuilder::session()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, ControlInterface { tx })?
+        .build()
+        .await?;
+    Ok(conn)
+}
+
+/// Emits `Idled` or `Resumed` for an idle notification
+pub async fn emit_idle(conn: Connection, timer: TimerInfo) -> anyhow::Result<()> {
+    let ctxt = SignalContext::new(&conn, OBJECT_PATH)?;
+    if timer.idle {
+        ControlInterface::idled(&ctxt, timer.id.to_string(), timer.timeout).await?;
+    } else {
+        ControlInterface::resumed(&ctxt, timer.id.to_string(), timer.timeout).await?;
+    }
+    Ok(())
+}
+
+/// Emits `Locked` when a locker started or exited
+pub async fn emit_locked(conn: Connection, locked: bool) -> anyhow::Result<()> {
+    let ctxt = SignalContext::new(&conn, OBJECT_PATH)?;
+    ControlInterface::locked(&ctxt, locked).await?;
+    Ok(())
+}
+
+#[dbus_proxy(
+    interface = "io.github.fishman.SleepWatcher",
+    default_service = "io.github.fishman.SleepWatcher",
+    default_path = "/io/github/fishman/SleepWatcher"
+)]
+trait Control {
+    fn reload(&self) -> zbus::Result<()>;
+    fn inhibit(&self, duration: u32, reason: &str) -> zbus::Result<()>;
+    fn uninhibit(&self) -> zbus::Result<()>;
+    fn get_status(&self) -> zbus::Result<HashMap<String, OwnedValue>>;
+    fn list_timers(&self) -> zbus::Result<Vec<(String, u32, bool, bool)>>;
+    fn trigger(&self, event: &str) -> zbus::Result<()>;
+}
+
+/// Commands of `sleepwatcher-rs ctl`
+#[derive(Subcommand, Debug)]
+pub enum CtlCommand {
+    /// Show the state of the daemon
+    Status,
+    /// Reload the config
+    Reload,
+    /// Inhibit idle, e.g. `inhibit 45m`. Without a duration until `uninhibit`
+    Inhibit {
+        #[arg(value_parser = utils::parse_duration)]
+        duration: Option<Duration>,
+        #[arg(long, default_value = "Inhibited with sleepwatcher-rs ctl")]
+        reason: String,
+    },
+    /// Release an inhibit created with `inhibit`
+    Uninhibit,
+    /// Run the handlers of an event, e.g. `trigger lock`
+    Trigger { event: String },
+    /// List the idle notifications of the config
+    ListTimers,
+}
+
+/// Runs a command against the running daemon and prints the result
+pub async fn ctl(command: CtlCommand, json: bool) -> anyhow::Result<()> {
+    let conn = Connection::session().await?;
+    let proxy = ControlProxy::new(&conn).await?;
+
+    match command {
+        CtlCommand::Status => {
+            let status = proxy.get_status().await?;
+            print_status(&status, json)?;
+        }
+        CtlCommand::Reload => proxy.reload().await?,
+        CtlCommand::Inhibit { duration, reason } => {
+            let seconds = duration.map_or(0, |duration| duration.as_secs());
+            proxy
+                .inhibit(seconds.try_into().unwrap_or(u32::MAX), &reason)
+                .await?
+        }
+        CtlCommand::Uninhibit => proxy.uninhibit().await?,
+        CtlCommand::Trigger { event } => proxy.trigger(&event).await?,
+        CtlCommand::ListTimers => {
+            let timers = proxy.list_timers().await?;
+            print_timers(&timers, json);
+        }
+    }
+    Ok(())
+}
+
+fn print_status(status: &HashMap<String, OwnedValue>, json: bool) -> anyhow::Result<()> {
+    fn field<T: TryFrom<OwnedValue>>(
+        status: &HashMap<String, OwnedValue>,
+        name: &str,
+    ) -> anyhow::Result<T> {
+        let value = status
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("The daemon didn't report {}", name))?;
+        T::try_from(value.clone())
+            .map_err(|_| anyhow::anyhow!("The daemon reported an invalid {}", name))
+    }
+
+    let on_battery: bool = field(status, "on_battery")?;
+    let locked: bool = field(status, "locked")?;
+    let config_error: String = field(status, "config_error")?;
+    let session: String = field(status, "session")?;
+    let inhibits: Vec<(String, String, u64)> = field(status, "inhibits")?;
+
+    if json {
+        let inhibits: Vec<String> = inhibits
+            .iter()
+            .map(|(source, reason, remaining)| {
+                format!(
+                    "{{\"source\":{},\"reason\":{},\"remaining\":{}}}",
+                    json_string(source),
+                    json_string(reason),
+                    json_optional(*remaining)
+                )
+            })
+            .collect();
+        println!(
+            "{{\"on_battery\":{},\"locked\":{},\"session\":{},\"config_error\":{},\"inhibits\":[{}]}}",
+            on_battery,
+            locked,
+            json_string_or_null(&session),
+            json_string_or_null(&config_error),
+            inhibits.join(",")
+        );
+        return Ok(());
+    }
+
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+    println!("On battery:   {}", yes_no(on_battery));
+    println!("Locked:       {}", yes_no(locked));
+    println!(
+        "Session:      {}",
+        if session.is_empty() { "-" } else { &session }
+    );
+    println!(
+        "Config error: {}",
+        if config_error.is_empty() {
+            "-"
+        } else {
+            &config_error
+        }
+    );
+    if inhibits.is_empty() {
+        println!("Inhibited:    no");
+    }
+    for (source, reason, remaining) in inhibits {
+        let until = match remaining {
+            0 => "until released".to_string(),
+            remaining => format!("{} left", utils::format_duration(remaining)),
+        };
+        println!("Inhibited:    {} ({}, {})", reason, source, until);
+    }
+    Ok(())
+}
+
+fn print_timers(timers: &[(String, u32, bool, bool)], json: bool) {
+    if json {
+        let timers: Vec<String> = timers
+            .iter()
+            .map(|(id, timeout, idle, idle_hint)| {
+                format!(
+                    "{{\"id\":{},\"timeout\":{},\"idle\":{},\"idle_hint\":{}}}",
+                    json_string(id),
+                    timeout,
+                    idle,
+                    idle_hint
+                )
+            })
+            .collect();
+        println!("[{}]", timers.join(","));
+        return;
+    }
+
+    for (id, timeout, idle, idle_hint) in timers {
+        println!(
+            "{}  {:>8}  {}{}",
+            id,
+            utils::format_duration((*timeout).into()),
+            if *idle { "idle" } else { "active" },
+            if *idle_hint { "  idle hint" } else { "" }
+        );
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Empty strings are reported as `null`
+fn json_string_or_null(value: &str) -> String {
+    if value.is_empty() {
+        "null".to_string()
+    } else {
+        json_string(value)
+    }
+}
+
+/// 0 is reported as `null`
+fn json_optional(value: u64) -> String {
+    if value == 0 {
+        "null".to_string()
+    } else {
+        value.to_string()
+    }
+}
@@ -1,11 +1,22 @@
-use super::types::Request;
-use futures::stream::StreamExt;
-use log::{debug, error};
-use tokio::sync::mpsc;
-use zbus::dbus_proxy;
-
-pub async fn upower_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
-    let conn = zbus::Connection::system().await?;
+use super::{
+    config,
+    types::{Event, MediaPlayer, MediaPlayersHandle, Request, Session},
+};
+use futures::stream::{Stream, StreamExt};
+use log::{debug, error, info, warn};
+use std::{collections::HashMap, env, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::timeout,
+};
+use zbus::{
+    dbus_proxy, fdo,
+    zvariant::{OwnedFd, OwnedObjectPath, Value},
+    CacheProperties, Connection,
+};
+
+pub async fn upower_watcher(conn: Connection, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
     let proxy = UPowerInterfaceProxy::new(&conn).await?;
 
     let state = proxy.on_battery().await?;
@@ -43,57 +54,533 @@ trait UPowerInterface {
     default_path = "/org/freedesktop/login1"
 )]
 trait LogindManagerInterface {
+    fn get_session(&self, session_id: &str) -> zbus::Result<OwnedObjectPath>;
+    #[dbus_proxy(name = "GetSessionByPID")]
+    fn get_session_by_pid(&self, pid: u32) -> zbus::Result<OwnedObjectPath>;
+    #[dbus_proxy(name = "GetUserByPID")]
+    fn get_user_by_pid(&self, pid: u32) -> zbus::Result<OwnedObjectPath>;
+    fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str) -> zbus::Result<OwnedFd>;
     #[dbus_proxy(signal)]
     fn prepare_for_sleep(&self, start: bool) -> fdo::Result<()>;
+    #[dbus_proxy(signal)]
+    fn session_removed(&self, session_id: String, object_path: OwnedObjectPath) -> fdo::Result<()>;
 }
 
 #[dbus_proxy(
     interface = "org.freedesktop.login1.Session",
-    default_service = "org.freedesktop.login1",
-    default_path = "/org/freedesktop/login1"
+    default_service = "org.freedesktop.login1"
 )]
 trait LogindSessionInterface {
+    #[dbus_proxy(property)]
+    fn id(&self) -> zbus::Result<String>;
+    fn set_idle_hint(&self, idle: bool) -> zbus::Result<()>;
+    fn set_locked_hint(&self, locked: bool) -> zbus::Result<()>;
     #[dbus_proxy(signal)]
     fn lock(&self) -> fdo::Result<()>;
     #[dbus_proxy(signal)]
     fn unlock(&self) -> fdo::Result<()>;
 }
 
-pub async fn logind_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
-    let conn = zbus::Connection::system().await?;
-    let session_proxy = LogindSessionInterfaceProxy::new(&conn).await?;
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.User",
+    default_service = "org.freedesktop.login1"
+)]
+trait LogindUserInterface {
+    #[dbus_proxy(property)]
+    fn display(&self) -> zbus::Result<(String, OwnedObjectPath)>;
+}
+
+/// Finds the logind session sleepwatcher-rs belongs to
+async fn resolve_session(
+    manager_proxy: &LogindManagerInterfaceProxy<'_>,
+) -> zbus::Result<OwnedObjectPath> {
+    if let Ok(session_id) = env::var("XDG_SESSION_ID") {
+        match manager_proxy.get_session(&session_id).await {
+            Ok(path) => return Ok(path),
+            Err(e) => debug!("Failed to get session {}: {}", session_id, e),
+        }
+    }
+    match manager_proxy.get_session_by_pid(std::process::id()).await {
+        Ok(path) => return Ok(path),
+        Err(e) => debug!("Failed to get session by pid: {}", e),
+    }
+    // Not part of a session, e.g. when started as a systemd user service. Use the display
+    // session of the user instead.
+    manager_proxy.get_session("auto").await
+}
+
+/// Takes a delay lock, so logind waits for the sleep handlers before suspending
+async fn sleep_inhibitor(manager_proxy: &LogindManagerInterfaceProxy<'_>) -> Option<OwnedFd> {
+    match manager_proxy
+        .inhibit("sleep", config::APP_NAME, "Running sleep handlers", "delay")
+        .await
+    {
+        Ok(fd) => Some(fd),
+        Err(e) => {
+            error!("Failed to take sleep delay lock: {}", e);
+            None
+        }
+    }
+}
+
+async fn session_proxy(
+    conn: &zbus::Connection,
+    path: OwnedObjectPath,
+) -> zbus::Result<LogindSessionInterfaceProxy<'static>> {
+    // Only signals and method calls are used, caching would fetch all properties first
+    LogindSessionInterfaceProxy::builder(conn)
+        .path(path)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+}
+
+/// The user sleepwatcher-rs runs as. logind only sends its signals on the real path of the
+/// user, not on the `user/self` alias.
+async fn user_proxy(
+    conn: &zbus::Connection,
+    manager_proxy: &LogindManagerInterfaceProxy<'_>,
+) -> zbus::Result<LogindUserInterfaceProxy<'static>> {
+    let path = manager_proxy.get_user_by_pid(std::process::id()).await?;
+    // Property change streams are fed by the property cache, so it stays enabled here
+    LogindUserInterfaceProxy::builder(conn)
+        .path(path)?
+        .build()
+        .await
+}
+
+async fn send_session(tx: &mpsc::Sender<Request>, session_proxy: &LogindSessionInterfaceProxy<'_>) {
+    let path = session_proxy.path().to_string();
+    match session_proxy.id().await {
+        Ok(id) => {
+            info!("Using logind session {} at {}", id, path);
+            let _ = tx.send(Request::Session(Session { id, path })).await;
+        }
+        Err(e) => {
+            error!("Failed to get id of session {}: {}", path, e);
+        }
+    }
+}
+
+/// A hint about the state of the session for logind
+#[derive(Debug, Clone, Copy)]
+pub enum SessionHint {
+    Idle(bool),
+    Locked(bool),
+}
+
+/// Sets session hints in the order they are sent, so a quick idle and resume can't reach
+/// logind the other way around. Hints are sent with the session path they belong to.
+pub fn session_hints(conn: Connection) -> mpsc::UnboundedSender<(String, SessionHint)> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<(String, SessionHint)>();
+    tokio::spawn(async move {
+        let mut proxy: Option<LogindSessionInterfaceProxy<'static>> = None;
+        while let Some((path, hint)) = rx.recv().await {
+            if proxy
+                .as_ref()
+                .is_none_or(|proxy| proxy.path().as_str() != path)
+            {
+                let object_path = OwnedObjectPath::try_from(path.as_str());
+                proxy = match object_path {
+                    Ok(object_path) => session_proxy(&conn, object_path).await,
+                    Err(e) => Err(e.into()),
+                }
+                .map_err(|e| error!("Failed to get logind session {}: {}", path, e))
+                .ok();
+            }
+            let Some(proxy) = &proxy else {
+                continue;
+            };
+            debug!("Setting {:?}", hint);
+            let result = match hint {
+                SessionHint::Idle(idle) => proxy.set_idle_hint(idle).await,
+                SessionHint::Locked(locked) => proxy.set_locked_hint(locked).await,
+            };
+            if let Err(e) = result {
+                error!("Failed to set {:?}: {}", hint, e);
+            }
+        }
+    });
+    tx
+}
+
+/// The session followed for `Lock` and `Unlock`
+struct SessionSignals {
+    proxy: LogindSessionInterfaceProxy<'static>,
+    lock: LockStream<'static>,
+    unlock: UnlockStream<'static>,
+}
+
+async fn follow_session(conn: &Connection, path: OwnedObjectPath) -> zbus::Result<SessionSignals> {
+    let proxy = session_proxy(conn, path).await?;
+    let lock = proxy.receive_lock().await?;
+    let unlock = proxy.receive_unlock().await?;
+    Ok(SessionSignals {
+        proxy,
+        lock,
+        unlock,
+    })
+}
+
+/// Next signal of a stream that may not exist yet, never ready without a stream
+async fn next_signal<S: Stream + Unpin>(stream: Option<&mut S>) -> Option<S::Item> {
+    match stream {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+pub async fn logind_watcher(
+    conn: Connection,
+    tx: mpsc::Sender<Request>,
+    max_sleep_delay: Duration,
+) -> anyhow::Result<()> {
     let manager_proxy = LogindManagerInterfaceProxy::new(&conn).await?;
+    let user_proxy = user_proxy(&conn, &manager_proxy)
+        .await
+        .map_err(|e| {
+            warn!(
+                "Failed to get logind user, display changes are ignored: {}",
+                e
+            )
+        })
+        .ok();
+    // Sleep handling doesn't need a session, e.g. when started over SSH or as a plain user
+    // service. The session is looked up again on every logind signal until one is found.
+    let mut session = match resolve_session(&manager_proxy).await {
+        Ok(path) => match follow_session(&conn, path).await {
+            Ok(session) => Some(session),
+            Err(e) => {
+                warn!("Failed to follow logind session: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            warn!(
+                "No logind session found, lock and unlock are ignored: {}",
+                e
+            );
+            None
+        }
+    };
+    if let Some(session) = &session {
+        send_session(&tx, &session.proxy).await;
+    }
 
     tokio::spawn(async move {
-        let mut lock_stream = session_proxy.receive_lock().await.unwrap();
-        let mut unlock_stream = session_proxy.receive_unlock().await.unwrap();
+        let mut inhibitor = sleep_inhibitor(&manager_proxy).await;
         let mut prepare_sleep_stream = manager_proxy.receive_prepare_for_sleep().await.unwrap();
+        let mut session_removed_stream = manager_proxy.receive_session_removed().await.unwrap();
+        let mut display_stream = match &user_proxy {
+            Some(user_proxy) => Some(user_proxy.receive_display_changed().await),
+            None => None,
+        };
 
         loop {
-            tokio::select! {
-                Some(_) = lock_stream.next() => {
+            let (lock_stream, unlock_stream) = match &mut session {
+                Some(session) => (Some(&mut session.lock), Some(&mut session.unlock)),
+                None => (None, None),
+            };
+            let session_changed = tokio::select! {
+                Some(_) = next_signal(lock_stream) => {
                     debug!("Lock signal received");
-                    let _ = tx.send(Request::LuaMethod("Lock".to_string())).await;
+                    let _ = tx.send(Request::Event(Event::Lock)).await;
+                    false
                 },
-                Some(_) = unlock_stream.next() => {
+                Some(_) = next_signal(unlock_stream) => {
                     debug!("Unlock signal received");
-                    let _ = tx.send(Request::LuaMethod("Unlock".to_string())).await;
+                    let _ = tx.send(Request::Event(Event::Unlock)).await;
+                    false
                 },
                 Some(signal) = prepare_sleep_stream.next() => {
                     debug!("Prepare for Sleep signal received");
                     match signal.args() {
                         Ok(args) => {
                             if *args.start() {
-                                let _ = tx.send(Request::LuaMethod("PrepareSleep".to_string())).await;
+                                let (done_tx, done_rx) = oneshot::channel();
+                                let _ = tx
+                                    .send(Request::BlockingEvent(Event::PrepareSleep, done_tx))
+                                    .await;
+                                if timeout(max_sleep_delay, done_rx).await.is_err() {
+                                    warn!(
+                                        "Sleep handlers did not finish within {:?}",
+                                        max_sleep_delay
+                                    );
+                                }
+                                debug!("Releasing sleep delay lock");
+                                inhibitor = None;
                             } else {
-                                let _ = tx.send(Request::LuaMethod("Wakeup".to_string())).await;
+                                if inhibitor.is_none() {
+                                    inhibitor = sleep_inhibitor(&manager_proxy).await;
+                                }
+                                let _ = tx.send(Request::Event(Event::Wakeup)).await;
                             }
                         }
                         Err(e) => {
                             error!("Error getting prepare_for_sleep args: {}", e);
                         }
                     }
+                    false
                 },
+                // The session may go away or the user may switch to another display session, in
+                // both cases the signals of the new session have to be followed.
+                Some(_) = session_removed_stream.next() => true,
+                Some(_) = next_signal(display_stream.as_mut()) => true,
+            };
+            if !session_changed && session.is_some() {
+                continue;
+            }
+
+            let path = match resolve_session(&manager_proxy).await {
+                Ok(path) => path,
+                Err(e) => {
+                    debug!("Failed to resolve session: {}", e);
+                    continue;
+                }
+            };
+            if session
+                .as_ref()
+                .is_some_and(|session| &path.as_ref() == session.proxy.path())
+            {
+                continue;
+            }
+            match follow_session(&conn, path).await {
+                Ok(new_session) => {
+                    send_session(&tx, &new_session.proxy).await;
+                    session = Some(new_session);
+                }
+                Err(e) => {
+                    error!("Failed to follow session change: {}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Power actions of logind, named after their methods on the Manager interface
+#[derive(Debug, Clone, Copy)]
+pub enum PowerAction {
+    Suspend,
+    Hibernate,
+    HybridSleep,
+    SuspendThenHibernate,
+    PowerOff,
+}
+
+impl PowerAction {
+    fn method(&self) -> &'static str {
+        match self {
+            PowerAction::Suspend => "Suspend",
+            PowerAction::Hibernate => "Hibernate",
+            PowerAction::HybridSleep => "HybridSleep",
+            PowerAction::SuspendThenHibernate => "SuspendThenHibernate",
+            PowerAction::PowerOff => "PowerOff",
+        }
+    }
+}
+
+/// Asks logind to perform a power action without interactive authorization
+pub async fn power_action(conn: &Connection, action: PowerAction) -> zbus::Result<()> {
+    debug!("Requesting {} from logind", action.method());
+    let proxy = LogindManagerInterfaceProxy::new(conn).await?;
+    proxy.inner().call(action.method(), &(false,)).await
+}
+
+/// Asks logind whether a power action is possible, the answer is one of "yes", "no", "challenge"
+/// or "na"
+pub async fn can_power_action(conn: &Connection, action: PowerAction) -> zbus::Result<String> {
+    let proxy = LogindManagerInterfaceProxy::new(conn).await?;
+    proxy
+        .inner()
+        .call(format!("Can{}", action.method()).as_str(), &())
+        .await
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait NotificationsInterface {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+}
+
+/// Shows a desktop notification on the session bus
+pub async fn notify(summary: String, body: String) -> anyhow::Result<()> {
+    let conn = zbus::Connection::session().await?;
+    let proxy = NotificationsInterfaceProxy::new(&conn).await?;
+
+    if let Err(e) = proxy
+        .notify(
+            config::APP_NAME,
+            0,
+            "",
+            &summary,
+            &body,
+            &[],
+            HashMap::new(),
+            -1,
+        )
+        .await
+    {
+        error!("Failed to send notification: {}", e);
+    }
+    Ok(())
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.timedate1",
+    default_service = "org.freedesktop.timedate1",
+    default_path = "/org/freedesktop/timedate1"
+)]
+trait TimedateInterface {
+    #[dbus_proxy(property)]
+    fn timezone(&self) -> zbus::Result<String>;
+}
+
+/// Sends the timezone whenever it's changed through systemd-timedated
+pub async fn timedate_watcher(conn: Connection, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let proxy = TimedateInterfaceProxy::new(&conn).await?;
+    let mut timezone_stream = proxy.receive_timezone_changed().await;
+
+    tokio::spawn(async move {
+        while let Some(timezone_changed) = timezone_stream.next().await {
+            match timezone_changed.get().await {
+                Ok(timezone) => {
+                    debug!("Timezone changed to {}", timezone);
+                    let _ = tx.send(Request::Timezone(timezone)).await;
+                }
+                Err(e) => {
+                    error!("Failed to get timezone: {}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+
+#[dbus_proxy(
+    interface = "org.mpris.MediaPlayer2.Player",
+    default_path = "/org/mpris/MediaPlayer2"
+)]
+trait MediaPlayer2Player {
+    fn pause(&self) -> zbus::Result<()>;
+    #[dbus_proxy(property)]
+    fn playback_status(&self) -> zbus::Result<String>;
+}
+
+/// Name of an MPRIS player without the `org.mpris.MediaPlayer2.` prefix, e.g. `mpv` or
+/// `firefox.instance_1_42`
+pub fn player_name(bus_name: &str) -> &str {
+    bus_name.strip_prefix(MPRIS_PREFIX).unwrap_or(bus_name)
+}
+
+/// Follows the playback status of a player until it's aborted
+async fn watch_player(
+    conn: &Connection,
+    bus_name: String,
+    tx: mpsc::Sender<Request>,
+    players: MediaPlayersHandle,
+) -> zbus::Result<JoinHandle<()>> {
+    let proxy = MediaPlayer2PlayerProxy::builder(conn)
+        .destination(bus_name.clone())?
+        .build()
+        .await?;
+    let status = proxy.playback_status().await.unwrap_or_default();
+    let mut status_stream = proxy.receive_playback_status_changed().await;
+    debug!("Found media player {}: {}", player_name(&bus_name), status);
+    players
+        .lock()
+        .unwrap()
+        .insert(bus_name.clone(), MediaPlayer { status, proxy });
+    let _ = tx.send(Request::MediaChanged).await;
+
+    Ok(tokio::spawn(async move {
+        while let Some(status_changed) = status_stream.next().await {
+            match status_changed.get().await {
+                Ok(status) => {
+                    debug!("{} is {}", player_name(&bus_name), status);
+                    if let Some(player) = players.lock().unwrap().get_mut(&bus_name) {
+                        player.status = status;
+                    }
+                    let _ = tx.send(Request::MediaChanged).await;
+                }
+                Err(e) => {
+                    error!("Failed to get playback status: {}", e);
+                }
+            }
+        }
+    }))
+}
+
+/// Tracks the MPRIS players on the session bus and their playback status
+pub async fn mpris_watcher(
+    tx: mpsc::Sender<Request>,
+    players: MediaPlayersHandle,
+) -> anyhow::Result<()> {
+    let conn = zbus::Connection::session().await?;
+    let dbus_proxy = fdo::DBusProxy::new(&conn).await?;
+    let mut name_owner_changed = dbus_proxy.receive_name_owner_changed().await?;
+
+    let mut watchers: HashMap<String, JoinHandle<()>> = HashMap::new();
+    for name in dbus_proxy.list_names().await? {
+        if !name.starts_with(MPRIS_PREFIX) {
+            continue;
+        }
+        match watch_player(&conn, name.to_string(), tx.clone(), players.clone()).await {
+            Ok(watcher) => {
+                watchers.insert(name.to_string(), watcher);
+            }
+            Err(e) => {
+                error!("Failed to watch media player {}: {}", name, e);
+            }
+        }
+    }
+
+    tokio::spawn(async move {
+        while let Some(signal) = name_owner_changed.next().await {
+            let args = match signal.args() {
+                Ok(args) => args,
+                Err(e) => {
+                    error!("Error getting name_owner_changed args: {}", e);
+                    continue;
+                }
+            };
+            let name = args.name().to_string();
+            if !name.starts_with(MPRIS_PREFIX) {
+                continue;
+            }
+
+            // A player that restarts may show up with a new owner before the old one is gone
+            if let Some(watcher) = watchers.remove(&name) {
+                watcher.abort();
+                players.lock().unwrap().remove(&name);
+                debug!("Media player {} vanished", player_name(&name));
+                let _ = tx.send(Request::MediaChanged).await;
+            }
+            if args.new_owner().is_some() {
+                match watch_player(&conn, name.clone(), tx.clone(), players.clone()).await {
+                    Ok(watcher) => {
+                        watchers.insert(name, watcher);
+                    }
+                    Err(e) => {
+                        error!("Failed to watch media player {}: {}", name, e);
+                    }
+                }
             }
         }
     });
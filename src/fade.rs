@@ -0,0 +1,98 @@
+//! Brightness animations on the gamma ramps, used to dim the screen before it's locked
+
+use std::{collections::HashMap, time::Duration};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep, Instant},
+};
+use uuid::Uuid;
+
+use crate::{
+    config,
+    types::{OutputListHandle, Request},
+};
+
+/// A running or finished fade. The brightness from before the fade is kept to reverse it.
+#[derive(Debug)]
+pub struct Fade {
+    /// Idle notification whose `resumed` reverses the fade
+    pub notification: Option<Uuid>,
+    /// Brightness of each output before the fade
+    restore: HashMap<u32, f64>,
+    task: JoinHandle<()>,
+}
+
+impl Fade {
+    /// Fades the brightness of all outputs to `brightness`. A previous fade is replaced, but
+    /// reversing the new one still goes back to the brightness from before the first.
+    pub fn start(
+        previous: Option<Fade>,
+        outputs: OutputListHandle,
+        brightness: f64,
+        duration: Duration,
+        notification: Option<Uuid>,
+        tx: mpsc::Sender<Request>,
+    ) -> Self {
+        let restore = match previous {
+            Some(previous) => {
+                previous.task.abort();
+                previous.restore
+            }
+            None => outputs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(reg_name, output)| (*reg_name, output.brightness()))
+                .collect(),
+        };
+        let from = outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(reg_name, output)| (*reg_name, output.brightness()))
+            .collect();
+        Self {
+            notification,
+            restore,
+            task: tokio::spawn(animate(outputs, from, brightness, duration, tx)),
+        }
+    }
+
+    /// Stops the fade and restores the brightness of the outputs right away
+    pub fn reverse(self, outputs: &OutputListHandle) {
+        self.task.abort();
+        for (reg_name, output) in outputs.lock().unwrap().iter_mut() {
+            if let Some(brightness) = self.restore.get(reg_name) {
+                output.set_brightness(*brightness);
+            }
+        }
+    }
+}
+
+async fn animate(
+    outputs: OutputListHandle,
+    from: HashMap<u32, f64>,
+    to: f64,
+    duration: Duration,
+    tx: mpsc::Sender<Request>,
+) {
+    let start = Instant::now();
+    loop {
+        let progress = if duration.is_zero() {
+            1.0
+        } else {
+            (start.elapsed().as_secs_f64() / duration.as_secs_f64()).min(1.0)
+        };
+        for (reg_name, output) in outputs.lock().unwrap().iter_mut() {
+            if let Some(from) = from.get(reg_name) {
+                output.set_brightness(from + (to - from) * progress);
+            }
+        }
+        let _ = tx.send(Request::Flush).await;
+        if progress >= 1.0 {
+            break;
+        }
+        sleep(Duration::from_millis(config::FADE_STEP_MS)).await;
+    }
+}
@@ -1,9 +1,9 @@
 use evdev::{Device, InputEventKind};
 use log::{debug, info};
-use std::path::Path;
+use std::{path::Path, time::Duration};
 use tokio::sync::mpsc;
 
-use crate::types::Request;
+use crate::{config, types::Request};
 
 #[derive(Debug)]
 pub struct JoystickHandler {
@@ -30,7 +30,11 @@ impl JoystickHandler {
                             match ev.kind() {
                                 InputEventKind::Key(_key) => {
                                     //debug!("Key event: {:?}, value: {}", key, ev.value());
-                                    self.tx.send(Request::Inhibit).await.unwrap();
+                                    self.tx.send(Request::Inhibit {
+                                        source: "joystick".to_string(),
+                                        reason: "Joystick activity".to_string(),
+                                        duration: Some(Duration::from_secs(config::TIMEOUT_SEC)),
+                                    }).await.unwrap();
                                 }
                                 // Ignore axis and synchronization events for now. For Axis events
                                 // it's not currently clear how to get absinfo
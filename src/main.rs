@@ -1,23 +1,26 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use env_logger::{Builder, Env};
 use inotify::{EventMask, Inotify, WatchMask};
-use log::{debug, error, info};
-use mlua::{AnyUserDataExt, Function, Lua, UserData, UserDataMethods};
+use log::{debug, error, info, warn};
+use mlua::{AnyUserDataExt, Function, Lua, Table, UserData, UserDataMethods, Value, VmState};
 use std::{
     collections::HashMap,
     env,
     fs::{self, File},
+    future::Future,
     io::Write,
     path::Path,
     process::Stdio,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
-    },
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use sysinfo::{PidExt, ProcessExt, System, SystemExt};
+use tokio::{
+    process::Command,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{sleep, sleep_until},
 };
-use sysinfo::{ProcessExt, System, SystemExt};
-use tokio::{process::Command, sync::mpsc, task::JoinHandle, time::sleep};
 use uuid::Uuid;
 use wayland::NotificationContext;
 use wayland_client::{
@@ -32,28 +35,38 @@ use wayland_protocols::{
     },
 };
 
-use crate::types::CallbackListHandle;
-use crate::types::LuaHandle;
-use crate::types::NotificationListHandle;
+use crate::types::{BlockingTaskListHandle, CallbackListHandle};
+use crate::types::{
+    GammaProfilesHandle, Inhibit, LuaEnv, MediaInhibitHandle, MediaPlayer, MediaPlayersHandle,
+    NightLightHandle, NotificationEntry, NotificationListHandle, OutputListHandle, StatusHandle,
+    TimerInfo,
+};
 
 mod color;
 mod config;
+mod control;
 mod dbus;
+mod fade;
 mod joystick_handler;
+mod screensaver;
 mod sunset;
 mod types;
 mod udev_handler;
 mod utils;
 mod wayland;
 
-use types::{Request, State};
+use color::Color;
+use control::CtlCommand;
+use dbus::{PowerAction, SessionHint};
+use fade::Fade;
+use sunset::{Location, NightLight};
+use types::{Event, EventCallback, Request, State, Status};
 use udev_handler::UdevHandler;
 
 lazy_static::lazy_static! {
     pub static ref INHIBIT_MANAGER: std::sync::Mutex<Option<zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1>> = std::sync::Mutex::new(None);
     pub static ref SURFACE: std::sync::Mutex<Option<WlSurface>> = std::sync::Mutex::new(None);
 }
-static IS_INHIBITED: AtomicBool = AtomicBool::new(false);
 
 fn ensure_config_file_exists(filename: &str) -> std::io::Result<()> {
     let config_path = utils::xdg_config_path(Some(filename.to_string()))?;
@@ -77,14 +90,44 @@ pub enum FileRequest {
 struct Args {
     #[arg(short, long, default_value = config::CONFIG_FILE_NAME)]
     config: String,
+    /// Show a desktop notification when the config fails to load
+    #[arg(long)]
+    notify_errors: bool,
+    /// Memory limit of the Lua state in MiB
+    #[arg(long, default_value_t = config::LUA_MEMORY_LIMIT_MB)]
+    lua_memory_limit: usize,
+    /// Time in milliseconds a Lua handler may run before it is aborted
+    #[arg(long, default_value_t = config::LUA_TIMEOUT_MS)]
+    lua_timeout: u64,
+    /// Time in milliseconds suspend is delayed at most for the sleep handlers to finish
+    #[arg(long, default_value_t = config::MAX_SLEEP_DELAY_MS)]
+    max_sleep_delay: u64,
+    /// Provide org.freedesktop.ScreenSaver, so applications can inhibit idle over D-Bus
+    #[arg(long)]
+    screensaver: bool,
+    #[command(subcommand)]
+    command: Option<Subcommands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Subcommands {
+    /// Control the running daemon
+    Ctl {
+        /// Print JSON instead of human readable output
+        #[arg(long)]
+        json: bool,
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
 }
 
 struct MyLuaFunctions {
-    wl_seat: Option<wl_seat::WlSeat>,
+    wl_seat: wl_seat::WlSeat,
     qh: QueueHandle<State>,
-    idle_notifier: Option<ext_idle_notifier_v1::ExtIdleNotifierV1>,
+    idle_notifier: ext_idle_notifier_v1::ExtIdleNotifierV1,
     tx: mpsc::Sender<Request>,
     notification_list: NotificationListHandle,
+    blocking_tasks: BlockingTaskListHandle,
     tasks: Mutex<HashMap<String, JoinHandle<anyhow::Result<()>>>>,
     //gamma_control: Option<zwlr_gamma_control_v1::ZwlrGammaControlV1>,
 }
@@ -92,36 +135,127 @@ struct MyLuaFunctions {
 #[derive(Clone, Debug)]
 struct LuaHelpers {
     on_battery: bool,
+    status: StatusHandle,
 }
 
 #[derive(Clone, Debug)]
-struct DbusHandler {
+struct EventHandler {
     handlers: CallbackListHandle,
 }
 
-impl UserData for DbusHandler {
+impl EventHandler {
+    fn register(
+        &self,
+        lua: &Lua,
+        name: &str,
+        handler: Function,
+        priority: i32,
+    ) -> mlua::Result<EventSubscription> {
+        if !Event::NAMES.contains(&name) {
+            return Err(mlua::Error::RuntimeError(format!(
+                "Unknown event {}, expected one of: {}",
+                name,
+                Event::NAMES.join(", ")
+            )));
+        }
+
+        let id = generate_uuid();
+        debug!(
+            "Registering handler {} for {} with priority {}",
+            id, name, priority
+        );
+        let callback = lua.create_registry_value(handler)?;
+        {
+            let mut map = self.handlers.lock().unwrap();
+            let callbacks = map.entry(name.to_string()).or_default();
+            callbacks.push(EventCallback {
+                id,
+                priority,
+                callback,
+            });
+            // The sort is stable, so handlers with the same priority keep their registration order
+            callbacks.sort_by_key(|callback| std::cmp::Reverse(callback.priority));
+        }
+
+        Ok(EventSubscription {
+            id,
+            event: name.to_string(),
+            handlers: self.handlers.clone(),
+        })
+    }
+}
+
+impl UserData for EventHandler {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("PrepareSleep", |_lua, this, fn_name: String| {
-            debug!("PrepareSleep callback");
-            let mut map = this.handlers.lock().unwrap();
-            map.insert("PrepareSleep".to_string(), fn_name);
-            Ok(())
-        });
-        methods.add_method("LockHandler", |_lua, this, fn_name: String| {
-            debug!("LcokHandler callback");
-            let mut map = this.handlers.lock().unwrap();
-            map.insert("LockHandler".to_string(), fn_name);
-            Ok(())
-        });
-        methods.add_method("UnlockHandler", |_lua, this, fn_name: String| {
-            debug!("UnlockHandler callback");
-            let mut map = this.handlers.lock().unwrap();
-            map.insert("UnlockHandler".to_string(), fn_name);
-            Ok(())
+        methods.add_method(
+            "on",
+            |lua, this, (name, handler, priority): (String, Function, Option<i32>)| {
+                this.register(lua, &name, handler, priority.unwrap_or_default())
+            },
+        );
+    }
+}
+
+/// Token returned by `Events:on`, used to unregister the handler again
+#[derive(Clone, Debug)]
+struct EventSubscription {
+    id: Uuid,
+    event: String,
+    handlers: CallbackListHandle,
+}
+
+impl UserData for EventSubscription {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("remove", |lua, this, (): ()| {
+            let removed = {
+                let mut map = this.handlers.lock().unwrap();
+                let callbacks = map.get_mut(&this.event);
+                callbacks.and_then(|callbacks| {
+                    let index = callbacks
+                        .iter()
+                        .position(|callback| callback.id == this.id)?;
+                    Some(callbacks.remove(index))
+                })
+            };
+
+            match removed {
+                Some(removed) => {
+                    debug!("Removed handler {} for {}", this.id, this.event);
+                    lua.remove_registry_value(removed.callback)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
         });
     }
 }
 
+/// Deprecated handler registration by global function name, superseded by `Events:on`
+#[derive(Clone, Debug)]
+struct DbusHandler {
+    events: EventHandler,
+}
+
+impl UserData for DbusHandler {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        for (method, event) in [
+            ("PrepareSleep", "prepare_sleep"),
+            ("LockHandler", "lock"),
+            ("UnlockHandler", "unlock"),
+        ] {
+            methods.add_method(method, move |lua, this, fn_name: String| {
+                warn!(
+                    "DbusHandler:{} is deprecated, use Events:on(\"{}\", {}) instead",
+                    method, event, fn_name
+                );
+                let handler: Function = lua.globals().get(fn_name)?;
+                this.events.register(lua, event, handler, 0)?;
+                Ok(())
+            });
+        }
+    }
+}
+
 impl UserData for LuaHelpers {
     // fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
     //     fields.add_field_method_get("on_battery", |_, this| Ok(this.on_battery));
@@ -137,6 +271,572 @@ impl UserData for LuaHelpers {
             info!("{}", message);
             Ok(())
         });
+        methods.add_method("config_error", |_lua, this, (): ()| {
+            Ok(this.status.lock().unwrap().config_error.clone())
+        });
+        methods.add_method("locked", |_lua, this, (): ()| {
+            Ok(this.status.lock().unwrap().locked)
+        });
+        methods.add_method("session", |lua, this, (): ()| {
+            let status = this.status.lock().unwrap();
+            match &status.session {
+                Some(session) => {
+                    let table = lua.create_table()?;
+                    table.set("id", session.id.clone())?;
+                    table.set("path", session.path.clone())?;
+                    Ok(Some(table))
+                }
+                None => Ok(None),
+            }
+        });
+    }
+}
+
+/// Power actions of logind, exposed to Lua as `Power`
+#[derive(Clone, Debug)]
+struct Power {
+    conn: zbus::Connection,
+}
+
+impl Power {
+    const ACTIONS: [(&'static str, PowerAction); 5] = [
+        ("suspend", PowerAction::Suspend),
+        ("hibernate", PowerAction::Hibernate),
+        ("hybrid_sleep", PowerAction::HybridSleep),
+        ("suspend_then_hibernate", PowerAction::SuspendThenHibernate),
+        ("poweroff", PowerAction::PowerOff),
+    ];
+}
+
+/// Runs a D-Bus call to completion. Lua handlers are called synchronously, so the result has
+/// to be available before returning to the script.
+fn block_on_dbus<F: Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+impl UserData for Power {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        for (name, action) in Power::ACTIONS {
+            methods.add_method(name, move |_lua, this, (): ()| {
+                match block_on_dbus(dbus::power_action(&this.conn, action)) {
+                    Ok(()) => Ok((true, None)),
+                    Err(e) => {
+                        error!("Failed to {}: {}", name, e);
+                        Ok((false, Some(e.to_string())))
+                    }
+                }
+            });
+            methods.add_method(format!("can_{}", name), move |_lua, this, (): ()| {
+                match block_on_dbus(dbus::can_power_action(&this.conn, action)) {
+                    Ok(answer) => Ok((Some(answer), None)),
+                    Err(e) => Ok((None, Some(e.to_string()))),
+                }
+            });
+        }
+    }
+}
+
+/// Source of the inhibit held while a media player is playing
+const MEDIA_INHIBIT_SOURCE: &str = "media";
+
+/// Whether an MPRIS player is one of `filter`, an empty filter matches every player. Players
+/// with several instances, like `firefox.instance_1_42`, match by their name without instance.
+fn player_matches(bus_name: &str, filter: &[String]) -> bool {
+    let name = dbus::player_name(bus_name);
+    filter.is_empty()
+        || filter.iter().any(|player| {
+            name.strip_prefix(player.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+}
+
+/// MPRIS media players on the session bus, exposed to Lua as `Media`
+#[derive(Clone, Debug)]
+struct Media {
+    players: MediaPlayersHandle,
+    inhibit: MediaInhibitHandle,
+    tx: mpsc::Sender<Request>,
+}
+
+impl UserData for Media {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("playing", |_lua, this, player: Option<String>| {
+            let filter: Vec<String> = player.into_iter().collect();
+            let players = this.players.lock().unwrap();
+            Ok(players
+                .iter()
+                .any(|(name, player)| player.playing() && player_matches(name, &filter)))
+        });
+        methods.add_method("pause_all", |_lua, this, (): ()| {
+            let playing: Vec<(String, MediaPlayer)> = this
+                .players
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, player)| player.playing())
+                .map(|(name, player)| (name.clone(), player.clone()))
+                .collect();
+            // Players are called in the background, a hung player must not block the handlers
+            let count = playing.len();
+            for (name, player) in playing {
+                tokio::spawn(async move {
+                    if let Err(e) = player.proxy.pause().await {
+                        error!("Failed to pause {}: {}", dbus::player_name(&name), e);
+                    }
+                });
+            }
+            Ok(count)
+        });
+        methods.add_method("inhibit_idle", |_lua, this, players: Value| {
+            let filter = match players {
+                Value::Nil | Value::Boolean(false) => None,
+                Value::Boolean(true) => Some(Vec::new()),
+                Value::Table(players) => Some(
+                    players
+                        .sequence_values::<String>()
+                        .collect::<mlua::Result<Vec<_>>>()?,
+                ),
+                _ => {
+                    return Err(mlua::Error::RuntimeError(
+                        "Expected a boolean or a list of player names".to_string(),
+                    ))
+                }
+            };
+            *this.inhibit.lock().unwrap() = filter;
+            let _ = this.tx.try_send(Request::MediaInhibit);
+            Ok(())
+        });
+    }
+}
+
+/// Gamma ramps of all outputs, exposed to Lua as `Gamma`
+#[derive(Clone, Debug)]
+struct Gamma {
+    outputs: OutputListHandle,
+    profiles: GammaProfilesHandle,
+    /// The night light keeps control of the temperature while it's enabled
+    night_light: NightLightHandle,
+    /// Idle notification whose `idled` callback is running
+    running_notification: Arc<Mutex<Option<Uuid>>>,
+    tx: mpsc::Sender<Request>,
+}
+
+/// Connected outputs, exposed to Lua as `Outputs`
+#[derive(Clone, Debug)]
+struct Outputs {
+    outputs: OutputListHandle,
+    tx: mpsc::Sender<Request>,
+}
+
+/// Commands that turn outputs on or off through the IPC of the running compositor, `None` if
+/// the compositor isn't known
+fn compositor_power_commands(targets: &[String], on: bool) -> Option<Vec<Vec<String>>> {
+    let state = if on { "on" } else { "off" };
+    if env::var_os("SWAYSOCK").is_some() {
+        Some(
+            targets
+                .iter()
+                .map(|target| {
+                    ["swaymsg", "output", target, "dpms", state]
+                        .map(String::from)
+                        .to_vec()
+                })
+                .collect(),
+        )
+    } else if env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        // hyprctl turns all outputs on or off without a name
+        Some(
+            targets
+                .iter()
+                .map(|target| {
+                    let mut command = ["hyprctl", "dispatch", "dpms", state]
+                        .map(String::from)
+                        .to_vec();
+                    if target != "*" {
+                        command.push(target.clone());
+                    }
+                    command
+                })
+                .collect(),
+        )
+    } else {
+        None
+    }
+}
+
+impl Outputs {
+    /// Turns the outputs matching `pattern` on or off with wlr-output-power-management, or
+    /// with the compositor's IPC for outputs without a power control
+    fn set_power(&self, pattern: &str, on: bool) -> Result<(), String> {
+        let mut controlled = 0;
+        let mut uncontrolled = Vec::new();
+        for output in self.outputs.lock().unwrap().values() {
+            let name = output.label();
+            if !utils::glob_match(pattern, &name) {
+                continue;
+            }
+            if output.set_power(on) {
+                controlled += 1;
+            } else {
+                uncontrolled.push(name);
+            }
+        }
+        if controlled > 0 {
+            let _ = self.tx.try_send(Request::Flush);
+        }
+        if uncontrolled.is_empty() {
+            return match controlled {
+                0 => Err(format!("No output matches {}", pattern)),
+                _ => Ok(()),
+            };
+        }
+
+        // The compositor can match all outputs itself, even ones that aren't known yet
+        let targets = if pattern == "*" && controlled == 0 {
+            vec![pattern.to_string()]
+        } else {
+            uncontrolled
+        };
+        let Some(commands) = compositor_power_commands(&targets, on) else {
+            return Err(
+                "The compositor supports neither wlr-output-power-management nor a known IPC"
+                    .to_string(),
+            );
+        };
+        for command in commands {
+            debug!("Setting output power with {:?}", command);
+            tokio::spawn(async move {
+                match Command::new(&command[0]).args(&command[1..]).status().await {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => warn!("{} exited with {}", command[0], status),
+                    Err(e) => error!("Failed to run {}: {}", command[0], e),
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl UserData for Outputs {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("list", |lua, this, (): ()| {
+            let outputs = this.outputs.lock().unwrap();
+            let mut outputs: Vec<_> = outputs.iter().collect();
+            outputs.sort_by_key(|(reg_name, _)| **reg_name);
+            let list = lua.create_table()?;
+            for (_, output) in outputs {
+                let table = lua.create_table()?;
+                table.set("name", output.label())?;
+                table.set("description", output.description.clone())?;
+                table.set("make", output.make.clone())?;
+                table.set("model", output.model.clone())?;
+                if let Some(mode) = output.mode {
+                    table.set("width", mode.width)?;
+                    table.set("height", mode.height)?;
+                    table.set("refresh", f64::from(mode.refresh) / 1000.0)?;
+                }
+                table.set("scale", output.scale)?;
+                table.set("powered", output.powered)?;
+                list.push(table)?;
+            }
+            Ok(list)
+        });
+        for (name, on) in [("power_off", false), ("power_on", true)] {
+            methods.add_method(name, move |_lua, this, pattern: Option<String>| {
+                let pattern = pattern.as_deref().unwrap_or("*");
+                match this.set_power(pattern, on) {
+                    Ok(()) => Ok((true, None)),
+                    Err(e) => {
+                        error!("Failed to {}: {}", name, e);
+                        Ok((false, Some(e)))
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Reads a color from a table like `{ temp = 4500, gamma = 1.0, brightness = 0.8, inverted =
+/// false }`, missing fields are taken from the default color.
+fn color_from_table(table: &Table) -> mlua::Result<Color> {
+    let default = Color::default();
+    let temp: u16 = table.get::<_, Option<u16>>("temp")?.unwrap_or(default.temp);
+    let gamma: f64 = table
+        .get::<_, Option<f64>>("gamma")?
+        .unwrap_or(default.gamma);
+    let brightness: f64 = table
+        .get::<_, Option<f64>>("brightness")?
+        .unwrap_or(default.brightness);
+    if gamma <= 0.0 {
+        return Err(mlua::Error::RuntimeError(format!(
+            "gamma has to be positive, got {}",
+            gamma
+        )));
+    }
+    Ok(Color {
+        temp: temp.clamp(1_000, 10_000),
+        gamma,
+        brightness: brightness.clamp(0.0, 1.0),
+        inverted: table
+            .get::<_, Option<bool>>("inverted")?
+            .unwrap_or(default.inverted),
+    })
+}
+
+impl UserData for Gamma {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "set",
+            |_lua, this, (table, pattern): (Table, Option<String>)| {
+                let color = color_from_table(&table)?;
+                // Without an explicit temperature the night light's current one is kept, instead
+                // of jumping to daylight until its next update
+                let keep_temp = table.get::<_, Option<u16>>("temp")?.is_none()
+                    && this.night_light.lock().unwrap().is_some();
+                {
+                    let mut profiles = this.profiles.lock().unwrap();
+                    match &pattern {
+                        // A color for all outputs replaces the profiles of single outputs
+                        None => profiles.clear(),
+                        Some(pattern) => profiles.retain(|(existing, _)| existing != pattern),
+                    }
+                    profiles.push((pattern.clone().unwrap_or_else(|| "*".to_string()), color));
+                }
+                let pattern = pattern.as_deref().unwrap_or("*");
+                for output in this.outputs.lock().unwrap().values_mut() {
+                    if utils::glob_match(pattern, &output.label()) {
+                        let temp = if keep_temp { output.temp() } else { color.temp };
+                        output.set_color(Color { temp, ..color });
+                    }
+                }
+                let _ = this.tx.try_send(Request::Flush);
+                Ok(())
+            },
+        );
+        methods.add_method("fade", |_lua, this, (brightness, duration): (f64, f64)| {
+            if !duration.is_finite() || duration < 0.0 {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "Invalid fade duration {}",
+                    duration
+                )));
+            }
+            let _ = this.tx.try_send(Request::Fade {
+                brightness: brightness.clamp(0.0, 1.0),
+                duration: Duration::from_secs_f64(duration),
+                notification: *this.running_notification.lock().unwrap(),
+            });
+            Ok(())
+        });
+        methods.add_method("cancel_fade", |_lua, this, (): ()| {
+            let _ = this.tx.try_send(Request::CancelFade);
+            Ok(())
+        });
+    }
+}
+
+/// Night light settings, exposed to Lua as `NightLight`
+#[derive(Clone, Debug)]
+struct NightLightControl {
+    settings: NightLightHandle,
+    status: StatusHandle,
+    tx: mpsc::Sender<Request>,
+}
+
+fn night_light_from_table(table: &Table) -> mlua::Result<NightLight> {
+    let latitude: Option<f64> = table.get("latitude")?;
+    let longitude: Option<f64> = table.get("longitude")?;
+    let location = match (latitude, longitude) {
+        (Some(latitude), Some(longitude))
+            if (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) =>
+        {
+            Some(Location {
+                latitude,
+                longitude,
+            })
+        }
+        (None, None) => None,
+        _ => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "Invalid location {:?}, {:?}",
+                latitude, longitude
+            )))
+        }
+    };
+    let night_light = NightLight {
+        location,
+        day_temp: table
+            .get::<_, Option<u16>>("day_temp")?
+            .unwrap_or(6500)
+            .clamp(1_000, 10_000),
+        night_temp: table
+            .get::<_, Option<u16>>("night_temp")?
+            .unwrap_or(4000)
+            .clamp(1_000, 10_000),
+        high: table.get::<_, Option<f64>>("high")?.unwrap_or(3.0),
+        low: table.get::<_, Option<f64>>("low")?.unwrap_or(-6.0),
+    };
+    if night_light.high <= night_light.low {
+        return Err(mlua::Error::RuntimeError(
+            "high has to be above low".to_string(),
+        ));
+    }
+    Ok(night_light)
+}
+
+impl NightLightControl {
+    /// The location of the config, or of the timezone if the config doesn't set one
+    fn location(&self) -> Option<Location> {
+        let settings = self.settings.lock().unwrap();
+        settings
+            .as_ref()
+            .and_then(|settings| settings.location)
+            .or(self.status.lock().unwrap().location)
+    }
+
+    fn require_location(&self) -> mlua::Result<Location> {
+        self.location().ok_or_else(|| {
+            mlua::Error::RuntimeError(
+                "The location is unknown, pass latitude and longitude to NightLight:enable"
+                    .to_string(),
+            )
+        })
+    }
+}
+
+impl UserData for NightLightControl {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("enable", |_lua, this, settings: Table| {
+            *this.settings.lock().unwrap() = Some(night_light_from_table(&settings)?);
+            let _ = this.tx.try_send(Request::NightLight);
+            Ok(())
+        });
+        methods.add_method("disable", |_lua, this, (): ()| {
+            *this.settings.lock().unwrap() = None;
+            let _ = this.tx.try_send(Request::NightLight);
+            Ok(())
+        });
+        methods.add_method("location", |_lua, this, (): ()| {
+            Ok(this
+                .location()
+                .map(|location| (location.latitude, location.longitude))
+                .unzip())
+        });
+        methods.add_method("elevation", |_lua, this, (): ()| {
+            Ok(sunset::elevation(this.require_location()?, sunset::now()))
+        });
+        methods.add_method("sun_times", |lua, this, time: Option<f64>| {
+            let location = this.require_location()?;
+            let times = sunset::sun_times(location, time.unwrap_or_else(sunset::now));
+            let table = lua.create_table()?;
+            table.set("dawn", times.dawn)?;
+            table.set("sunrise", times.sunrise)?;
+            table.set("noon", times.noon)?;
+            table.set("sunset", times.sunset)?;
+            table.set("dusk", times.dusk)?;
+            Ok(table)
+        });
+    }
+}
+
+/// Lua handle for a single idle notification returned by `IdleNotifier:get_notification`
+#[derive(Clone, Debug)]
+struct IdleNotificationHandle {
+    uuid: Uuid,
+    wl_seat: wl_seat::WlSeat,
+    idle_notifier: ext_idle_notifier_v1::ExtIdleNotifierV1,
+    qh: QueueHandle<State>,
+    tx: mpsc::Sender<Request>,
+    notification_list: NotificationListHandle,
+}
+
+impl IdleNotificationHandle {
+    fn create_notification(
+        &self,
+        timeout: u32,
+        generation: u32,
+    ) -> mlua::Result<ext_idle_notification_v1::ExtIdleNotificationV1> {
+        let timeout_ms = timeout.checked_mul(1000).ok_or_else(|| {
+            mlua::Error::RuntimeError(format!("Idle timeout of {} seconds is too large", timeout))
+        })?;
+
+        Ok(self.idle_notifier.get_idle_notification(
+            timeout_ms,
+            &self.wl_seat,
+            &self.qh,
+            NotificationContext {
+                uuid: self.uuid,
+                generation,
+            },
+        ))
+    }
+
+    fn flush(&self) {
+        // The Wayland loop may be blocked on reading, so the requests have to be flushed
+        // explicitly to reach the compositor.
+        let _ = self.tx.try_send(Request::Flush);
+    }
+}
+
+impl UserData for IdleNotificationHandle {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("cancel", |lua, this, (): ()| {
+            let entry = this.notification_list.lock().unwrap().remove(&this.uuid);
+            if let Some(entry) = entry {
+                debug!("Cancelling idle notification {}", this.uuid);
+                entry.notification.destroy();
+                lua.remove_registry_value(entry.callback)?;
+                if entry.idle_hint && entry.idle {
+                    let _ = this.tx.try_send(Request::UpdateIdleHint);
+                }
+                this.flush();
+            }
+            Ok(())
+        });
+        methods.add_method("set_timeout", |_lua, this, timeout: u32| {
+            let mut map = this.notification_list.lock().unwrap();
+            let entry = map.get_mut(&this.uuid).ok_or_else(|| {
+                mlua::Error::RuntimeError("Idle notification was cancelled".to_string())
+            })?;
+
+            debug!(
+                "Changing idle notification {} timeout to {} seconds",
+                this.uuid, timeout
+            );
+            // ext-idle-notify has no way to change the timeout of an existing notification, so
+            // it is replaced with a new one using the same uuid. Events of the old one that are
+            // still queued are told apart by the generation.
+            let generation = entry.generation.wrapping_add(1);
+            let notification = this.create_notification(timeout, generation)?;
+            entry.notification.destroy();
+            entry.notification = notification;
+            entry.generation = generation;
+            entry.timeout = timeout;
+            if entry.idle_hint && entry.idle {
+                let _ = this.tx.try_send(Request::UpdateIdleHint);
+            }
+            entry.idle = false;
+            this.flush();
+            Ok(())
+        });
+        methods.add_method("set_idle_hint", |_lua, this, enabled: bool| {
+            let mut map = this.notification_list.lock().unwrap();
+            let entry = map.get_mut(&this.uuid).ok_or_else(|| {
+                mlua::Error::RuntimeError("Idle notification was cancelled".to_string())
+            })?;
+            if entry.idle && entry.idle_hint != enabled {
+                let _ = this.tx.try_send(Request::UpdateIdleHint);
+            }
+            entry.idle_hint = enabled;
+            Ok(())
+        });
+        methods.add_method("is_idle", |_lua, this, (): ()| {
+            let map = this.notification_list.lock().unwrap();
+            Ok(map.get(&this.uuid).is_some_and(|entry| entry.idle))
+        });
+        methods.add_method("timeout", |_lua, this, (): ()| {
+            let map = this.notification_list.lock().unwrap();
+            Ok(map.get(&this.uuid).map(|entry| entry.timeout))
+        });
     }
 }
 
@@ -144,28 +844,49 @@ impl UserData for MyLuaFunctions {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method(
             "get_notification",
-            |_lua, this, (timeout, fn_name): (i32, String)| {
-                let ctx = NotificationContext {
-                    uuid: generate_uuid(),
+            |lua, this, (timeout, callback): (u32, Value)| {
+                // Plain function names are still accepted for older configs and are resolved
+                // once, at registration time.
+                let handler: Function = match callback {
+                    Value::Function(handler) => handler,
+                    Value::String(fn_name) => lua.globals().get(fn_name)?,
+                    other => {
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "get_notification expects a function, got {}",
+                            other.type_name()
+                        )))
+                    }
                 };
+                let uuid = generate_uuid();
 
-                debug!(
-                    "get_notification id: {} fn: {} timeout: {} seconds",
-                    ctx.uuid, fn_name, timeout
-                );
-                let notification = this.idle_notifier.as_ref().unwrap().get_idle_notification(
-                    (timeout * 1000).try_into().unwrap(),
-                    this.wl_seat.as_ref().unwrap(),
-                    &this.qh,
-                    ctx.clone(),
-                );
+                debug!("get_notification id: {} timeout: {} seconds", uuid, timeout);
+                let handle = IdleNotificationHandle {
+                    uuid,
+                    wl_seat: this.wl_seat.clone(),
+                    idle_notifier: this.idle_notifier.clone(),
+                    qh: this.qh.clone(),
+                    tx: this.tx.clone(),
+                    notification_list: this.notification_list.clone(),
+                };
+                let notification = handle.create_notification(timeout, 0)?;
+                let callback = lua.create_registry_value(handler)?;
 
                 {
                     let mut map = this.notification_list.lock().unwrap();
-                    map.insert(ctx.uuid, (fn_name, notification));
+                    map.insert(
+                        uuid,
+                        NotificationEntry {
+                            callback,
+                            notification,
+                            generation: 0,
+                            timeout,
+                            idle: false,
+                            idle_hint: false,
+                        },
+                    );
                 }
 
-                Ok(())
+                Ok(handle)
             },
         );
 
@@ -214,17 +935,34 @@ impl UserData for MyLuaFunctions {
             Ok(())
         });
 
-        methods.add_async_method("run_once", |_lua, _this, command: String| async move {
-            debug!("run_once function called {}", command.clone());
-            let s = System::new_all();
-            let (cmd_name, _) = utils::get_args(command.clone());
+        fn is_running(command: &str) -> bool {
+            let mut s = System::new();
+            s.refresh_processes();
+            let (cmd_name, _) = utils::get_args(command.to_string());
 
-            // Check if the process is already running
             let is_running = s
                 .processes_by_exact_name(&cmd_name)
                 .any(|p| p.name() == cmd_name);
+            is_running
+        }
+
+        /// PIDs of the processes running `command`
+        fn running_pids(command: &str) -> Vec<u32> {
+            let mut s = System::new();
+            s.refresh_processes();
+            let (cmd_name, _) = utils::get_args(command.to_string());
+
+            s.processes_by_exact_name(&cmd_name)
+                .filter(|p| p.name() == cmd_name)
+                .map(|p| p.pid().as_u32())
+                .collect()
+        }
+
+        methods.add_async_method("run_once", |_lua, _this, command: String| async move {
+            debug!("run_once function called {}", command.clone());
 
-            if !is_running {
+            // Check if the process is already running
+            if !is_running(&command) {
                 //let mut tasks = this.tasks.lock();
                 //if !tasks.contains_key(&cmd) {
                 let _handle = run(command.clone()).await;
@@ -233,6 +971,110 @@ impl UserData for MyLuaFunctions {
             }
             Ok(())
         });
+
+        methods.add_async_method(
+            "run_wait",
+            |_lua, this, (command, options): (String, Option<Table>)| async move {
+                debug!("run_wait function called {}", command.clone());
+                check_command(&command)?;
+                let wait_timeout = wait_timeout(&options)?;
+                let once = match &options {
+                    Some(options) => options.get::<_, Option<bool>>("once")?.unwrap_or(false),
+                    None => false,
+                };
+
+                if once && is_running(&command) {
+                    return Ok(());
+                }
+
+                let handle = run(command.clone()).await;
+                this.wait_for(command, handle, wait_timeout);
+                Ok(())
+            },
+        );
+
+        methods.add_async_method(
+            "lock",
+            |_lua, this, (command, options): (String, Option<Table>)| async move {
+                debug!("lock function called {}", command.clone());
+                check_command(&command)?;
+                let wait_timeout = wait_timeout(&options)?;
+
+                if is_running(&command) {
+                    return Ok(false);
+                }
+
+                let handle = run(command.clone()).await;
+                let (exited_tx, exited_rx) = oneshot::channel::<()>();
+                let tx = this.tx.clone();
+                let locker = command.clone();
+                tokio::spawn(async move {
+                    let _ = tx.send(Request::Locked(true)).await;
+                    let _ = handle.await;
+                    let _ = exited_tx.send(());
+                    // Lockers like `swaylock -f` fork once the screen is locked. The forked
+                    // process is looked up by name once and then followed by its PID.
+                    let pids = tokio::task::spawn_blocking(move || running_pids(&locker))
+                        .await
+                        .unwrap_or_default();
+                    for pid in pids {
+                        let pid = nix::unistd::Pid::from_raw(pid as i32);
+                        while nix::sys::signal::kill(pid, None).is_ok() {
+                            sleep(Duration::from_secs(1)).await;
+                        }
+                    }
+                    let _ = tx.send(Request::Locked(false)).await;
+                });
+                this.wait_for(command, exited_rx, wait_timeout);
+                Ok(true)
+            },
+        );
+    }
+}
+
+/// Rejects commands without a program, they can't be split into a program and its arguments
+fn check_command(command: &str) -> mlua::Result<()> {
+    if command.trim().is_empty() {
+        return Err(mlua::Error::RuntimeError(
+            "Command must not be empty".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn wait_timeout(options: &Option<Table>) -> mlua::Result<Option<Duration>> {
+    let wait_timeout = match options {
+        Some(options) => options.get::<_, Option<f64>>("timeout")?,
+        None => None,
+    };
+    wait_timeout
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(mlua::Error::external)
+}
+
+impl MyLuaFunctions {
+    /// Delays the blocking event currently being handled until `task` has finished
+    fn wait_for<F>(&self, command: String, task: F, wait_timeout: Option<Duration>)
+    where
+        F: Future + Send + 'static,
+    {
+        // Only handlers of blocking events, like prepare_sleep, wait for the command
+        let mut blocking_tasks = self.blocking_tasks.lock().unwrap();
+        if let Some(tasks) = blocking_tasks.as_mut() {
+            tasks.push(tokio::spawn(async move {
+                match wait_timeout {
+                    Some(wait_timeout) => {
+                        if tokio::time::timeout(wait_timeout, task).await.is_err() {
+                            warn!("{} did not finish within {:?}", command, wait_timeout);
+                        }
+                    }
+                    None => {
+                        task.await;
+                    }
+                }
+            }));
+        }
     }
 }
 
@@ -281,78 +1123,329 @@ pub async fn filewatcher_run(config_path: &Path, tx: mpsc::Sender<Request>) -> a
     Ok(())
 }
 
-fn lua_load_config(lua: &Lua) -> anyhow::Result<Result<(), mlua::Error>> {
-    let args = Args::parse();
+/// A sandboxed Lua VM together with the registries its config filled in. Every config load
+/// creates a new runtime, so nothing leaks from one configuration into the next.
+#[derive(Debug)]
+struct LuaRuntime {
+    lua: Lua,
+    notification_list: NotificationListHandle,
+    event_handlers: CallbackListHandle,
+    blocking_tasks: BlockingTaskListHandle,
+    /// Point in time after which the running handler is aborted
+    deadline: Arc<Mutex<Option<Instant>>>,
+    timeout: Duration,
+    media_inhibit: MediaInhibitHandle,
+    night_light: NightLightHandle,
+    running_notification: Arc<Mutex<Option<Uuid>>>,
+    gamma_profiles: GammaProfilesHandle,
+}
+
+impl LuaRuntime {
+    fn new(
+        env: LuaEnv,
+        on_battery: bool,
+        status: StatusHandle,
+        system_bus: zbus::Connection,
+        media: MediaPlayersHandle,
+    ) -> anyhow::Result<Self> {
+        let args = Args::parse();
+        let lua = Lua::new();
+        lua.sandbox(true)?;
+        lua.set_memory_limit(args.lua_memory_limit * 1024 * 1024)?;
+
+        let deadline: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
+        {
+            let deadline = deadline.clone();
+            lua.set_interrupt(move |_lua| match *deadline.lock().unwrap() {
+                Some(deadline) if Instant::now() > deadline => Err(mlua::Error::RuntimeError(
+                    "Lua handler exceeded its execution time limit".to_string(),
+                )),
+                _ => Ok(VmState::Continue),
+            });
+        }
+
+        let notification_list: NotificationListHandle = Arc::new(Mutex::new(HashMap::new()));
+        let event_handlers: CallbackListHandle = Arc::new(Mutex::new(HashMap::new()));
+        let blocking_tasks: BlockingTaskListHandle = Arc::new(Mutex::new(None));
+        let media_inhibit: MediaInhibitHandle = Arc::new(Mutex::new(None));
+        let night_light: NightLightHandle = Arc::new(Mutex::new(None));
+        let running_notification: Arc<Mutex<Option<Uuid>>> = Arc::new(Mutex::new(None));
+        let gamma_profiles: GammaProfilesHandle = Arc::new(Mutex::new(Vec::new()));
+        let events = EventHandler {
+            handlers: event_handlers.clone(),
+        };
+        let my_lua_functions = MyLuaFunctions {
+            wl_seat: env.wl_seat,
+            idle_notifier: env.idle_notifier,
+            qh: env.qh,
+            notification_list: notification_list.clone(),
+            blocking_tasks: blocking_tasks.clone(),
+            tx: env.tx.clone(),
+            tasks: Mutex::new(HashMap::new()),
+        };
+
+        {
+            let globals = lua.globals();
+            globals.set("IdleNotifier", my_lua_functions)?;
+            globals.set(
+                "Helpers",
+                LuaHelpers {
+                    on_battery,
+                    status: status.clone(),
+                },
+            )?;
+            globals.set("Events", events.clone())?;
+            globals.set("DbusHandler", DbusHandler { events })?;
+            globals.set("Power", Power { conn: system_bus })?;
+            globals.set(
+                "Outputs",
+                Outputs {
+                    outputs: env.outputs.clone(),
+                    tx: env.tx.clone(),
+                },
+            )?;
+            globals.set(
+                "Gamma",
+                Gamma {
+                    outputs: env.outputs.clone(),
+                    profiles: gamma_profiles.clone(),
+                    night_light: night_light.clone(),
+                    running_notification: running_notification.clone(),
+                    tx: env.tx.clone(),
+                },
+            )?;
+            globals.set(
+                "Media",
+                Media {
+                    players: media,
+                    inhibit: media_inhibit.clone(),
+                    tx: env.tx.clone(),
+                },
+            )?;
+            globals.set(
+                "NightLight",
+                NightLightControl {
+                    settings: night_light.clone(),
+                    status,
+                    tx: env.tx,
+                },
+            )?;
+        }
+
+        Ok(Self {
+            lua,
+            notification_list,
+            event_handlers,
+            blocking_tasks,
+            deadline,
+            timeout: Duration::from_millis(args.lua_timeout),
+            media_inhibit,
+            night_light,
+            running_notification,
+            gamma_profiles,
+        })
+    }
+
+    /// Runs `f` with the execution time limit armed. Every call into Lua has to go through here.
+    fn guarded<R>(&self, f: impl FnOnce() -> mlua::Result<R>) -> mlua::Result<R> {
+        *self.deadline.lock().unwrap() = Some(Instant::now() + self.timeout);
+        let result = f();
+        *self.deadline.lock().unwrap() = None;
+        result
+    }
+
+    fn load_config(&self) -> anyhow::Result<()> {
+        let args = Args::parse();
+
+        let config_path = utils::xdg_config_path(Some(args.config))?;
+        let config = fs::read_to_string(config_path)?;
+        self.guarded(|| self.lua.load(&config).exec())?;
 
-    let config_path = utils::xdg_config_path(Some(args.config))?;
-    let config = fs::read_to_string(config_path)?;
-    let result = lua.load(&config).exec();
-    match result {
-        Ok(_) => {}
-        Err(ref e) => {
-            error!("Error loading config: {}", e);
+        Ok(())
+    }
+
+    /// Calls the callback of an idle notification. Returns the new idle hint if the
+    /// notification is used for logind's idle hint.
+    fn idle_callback(&self, uuid: Uuid, idle: bool) -> Option<bool> {
+        let (handler, idle_hint): (Function, Option<bool>) = {
+            let mut map = self.notification_list.lock().unwrap();
+            let Some(entry) = map.get_mut(&uuid) else {
+                debug!("No idle callback registered for {}", uuid);
+                return None;
+            };
+            entry.idle = idle;
+            let handler = match self.lua.registry_value(&entry.callback) {
+                Ok(handler) => handler,
+                Err(e) => {
+                    error!("Idle callback for {} is not a function: {}", uuid, e);
+                    return None;
+                }
+            };
+            let idle_hint = entry
+                .idle_hint
+                .then(|| map.values().any(|entry| entry.idle_hint && entry.idle));
+            (handler, idle_hint)
+        };
+        // The map lock is released before calling into Lua, so callbacks can use their
+        // notification handles.
+        let event = if idle { "idled" } else { "resumed" };
+        // Fades started from here are reversed once the notification resumes
+        *self.running_notification.lock().unwrap() = idle.then_some(uuid);
+        if let Err(e) = self.guarded(|| handler.call::<_, ()>(event)) {
+            error!("Error in idle callback: {}", e);
         }
+        *self.running_notification.lock().unwrap() = None;
+        idle_hint
     }
 
-    Ok(result)
-}
+    /// Color of the latest profile matching the output name
+    fn gamma_profile(&self, output: &str) -> Option<Color> {
+        self.gamma_profiles
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(pattern, _)| utils::glob_match(pattern, output))
+            .map(|(_, color)| *color)
+    }
 
-pub fn lua_init(state: &mut State) -> anyhow::Result<()> {
-    let lua = state.lua.lock().unwrap();
-    lua.sandbox(true)?;
-    let my_lua_functions = MyLuaFunctions {
-        wl_seat: state.wl_seat.clone(),
-        idle_notifier: state.idle_notifier.clone(),
-        qh: state.qh.clone(),
-        notification_list: state.notification_list.clone(),
-        tx: state.tx.clone(),
-        tasks: Mutex::new(HashMap::new()),
-    };
+    /// Lists the idle notifications of the config, shortest timeout first
+    fn timers(&self) -> Vec<TimerInfo> {
+        let map = self.notification_list.lock().unwrap();
+        let mut timers: Vec<TimerInfo> = map
+            .iter()
+            .map(|(id, entry)| TimerInfo {
+                id: *id,
+                timeout: entry.timeout,
+                idle: entry.idle,
+                idle_hint: entry.idle_hint,
+            })
+            .collect();
+        timers.sort_by_key(|timer| timer.timeout);
+        timers
+    }
 
-    let globals = lua.globals();
-    globals.set("IdleNotifier", my_lua_functions)?;
-    globals.set("Helpers", LuaHelpers { on_battery: true })?;
-    let _ = globals.set(
-        "DbusHandler",
-        DbusHandler {
-            handlers: state.dbus_handlers.clone(),
-        },
-    );
-    let _ = lua_load_config(&lua)?;
+    /// Calls all handlers registered for an event. A failing handler doesn't stop the
+    /// remaining ones from running.
+    fn emit(&self, event: &Event) {
+        let handlers: Vec<Function> = {
+            let map = self.event_handlers.lock().unwrap();
+            map.get(event.name())
+                .into_iter()
+                .flatten()
+                .filter_map(
+                    |callback| match self.lua.registry_value(&callback.callback) {
+                        Ok(handler) => Some(handler),
+                        Err(e) => {
+                            error!("Handler for {} is not a function: {}", event.name(), e);
+                            None
+                        }
+                    },
+                )
+                .collect()
+        };
+        if handlers.is_empty() {
+            debug!("No handler registered for {}", event.name());
+            return;
+        }
 
-    Ok(())
+        for handler in handlers {
+            let result = self.guarded(|| {
+                let arg = match event {
+                    Event::PowerChanged { on_battery } => Value::Boolean(*on_battery),
+                    Event::MediaChanged { playing } => Value::Boolean(*playing),
+                    Event::DeviceAdded(name)
+                    | Event::DeviceRemoved(name)
+                    | Event::OutputAdded(name)
+                    | Event::OutputRemoved(name) => Value::String(self.lua.create_string(name)?),
+                    _ => Value::Nil,
+                };
+                handler.call::<_, ()>(arg)
+            });
+            if let Err(e) = result {
+                error!("Error in {} handler: {}", event.name(), e);
+            }
+        }
+    }
+
+    /// Calls all handlers of an event and returns the tasks waiting for the commands the
+    /// handlers started with `run_wait`
+    fn emit_blocking(&self, event: &Event) -> Vec<JoinHandle<()>> {
+        *self.blocking_tasks.lock().unwrap() = Some(Vec::new());
+        self.emit(event);
+        self.blocking_tasks
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_default()
+    }
+
+    /// Whether an event comes from the current Wayland object of a notification. Cancelled
+    /// notifications count as current, their events are ignored later on.
+    fn is_current(&self, uuid: Uuid, generation: u32) -> bool {
+        let map = self.notification_list.lock().unwrap();
+        map.get(&uuid)
+            .is_none_or(|entry| entry.generation == generation)
+    }
+
+    /// Whether any idle notification used for the idle hint is idle
+    fn idle_hint(&self) -> bool {
+        let map = self.notification_list.lock().unwrap();
+        map.values().any(|entry| entry.idle_hint && entry.idle)
+    }
+
+    /// Destroys all idle notifications and forgets every registered callback
+    fn teardown(&self) {
+        let mut map = self.notification_list.lock().unwrap();
+        for (_, entry) in map.drain() {
+            entry.notification.destroy();
+            let _ = self.lua.remove_registry_value(entry.callback);
+        }
+        let mut handlers = self.event_handlers.lock().unwrap();
+        for (_, callbacks) in handlers.drain() {
+            for callback in callbacks {
+                let _ = self.lua.remove_registry_value(callback.callback);
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct WaylandRunner {
     connection: Connection,
     qhandle: QueueHandle<State>,
-    lua: LuaHandle,
     tx: mpsc::Sender<Request>,
-    notification_list: NotificationListHandle,
-    dbus_handlers: CallbackListHandle,
+    status: StatusHandle,
+    system_bus: zbus::Connection,
+    media: MediaPlayersHandle,
+    outputs: OutputListHandle,
+    /// Session bus connection serving the control interface, if the name could be claimed
+    control_bus: Option<zbus::Connection>,
+    session_hints: mpsc::UnboundedSender<(String, SessionHint)>,
+    /// Timers releasing inhibits with a duration, one per source
+    inhibit_expiries: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
 }
 
 impl WaylandRunner {
     pub fn new(
         connection: Connection,
         qhandle: QueueHandle<State>,
-        lua: LuaHandle,
         tx: mpsc::Sender<Request>,
+        system_bus: zbus::Connection,
+        control_bus: Option<zbus::Connection>,
+        media: MediaPlayersHandle,
     ) -> Self {
-        let map: HashMap<Uuid, (String, ext_idle_notification_v1::ExtIdleNotificationV1)> =
-            HashMap::new();
-
-        let notification_list = Arc::new(Mutex::new(map));
-        let dbus_handlers = Arc::new(Mutex::new(HashMap::new()));
-
         Self {
             connection,
             qhandle,
-            lua,
             tx,
-            notification_list,
-            dbus_handlers,
+            status: Arc::new(Mutex::new(Status::default())),
+            session_hints: dbus::session_hints(system_bus.clone()),
+            inhibit_expiries: Arc::new(Mutex::new(HashMap::new())),
+            system_bus,
+            control_bus,
+            media,
+            outputs: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -367,11 +1460,10 @@ impl WaylandRunner {
             wl_seat: None,
             idle_notifier: None,
             qh: self.qhandle.clone(),
-            notification_list: self.notification_list.clone(),
-            dbus_handlers: self.dbus_handlers.clone(),
             tx: self.tx.clone(),
-            lua: self.lua.clone(),
-            outputs: HashMap::new(),
+            outputs: self.outputs.clone(),
+            gamma_manager: None,
+            power_manager: None,
         };
 
         Ok(tokio::task::spawn_blocking(move || loop {
@@ -379,58 +1471,210 @@ impl WaylandRunner {
         }))
     }
 
+    /// Runs all requests, including every call into Lua. The Lua runtime is owned by this loop
+    /// only, so Lua handlers never run on the Wayland dispatch thread.
     pub async fn process_command(&self, rx: &mut mpsc::Receiver<Request>) -> anyhow::Result<()> {
+        // The Wayland globals are only known once the registry has been processed, keep them
+        // around to be able to create new runtimes on reload.
+        let mut lua_env: Option<LuaEnv> = None;
+        let mut runtime: Option<LuaRuntime> = None;
+        let mut inhibitor: Option<ZwpIdleInhibitorV1> = None;
+        let mut night_light: Option<JoinHandle<()>> = None;
+        let mut fade: Option<Fade> = None;
+
         while let Some(event) = rx.recv().await {
             match event {
-                Request::Reset => {
-                    debug!("Reloading config");
+                Request::LuaInit(env) => {
+                    debug!("Loading lua config");
+                    self.lua_reload(&mut runtime, env.clone());
+                    lua_env = Some(env);
+                }
+                Request::Reset => match &lua_env {
+                    Some(env) => {
+                        debug!("Reloading config");
+                        self.lua_reload(&mut runtime, env.clone());
+                    }
+                    None => {
+                        debug!("Lua is not initialized yet, skipping reload");
+                    }
+                },
+                Request::Idled(uuid, generation) | Request::Resumed(uuid, generation) => {
+                    if runtime
+                        .as_ref()
+                        .is_some_and(|runtime| !runtime.is_current(uuid, generation))
                     {
-                        let map = self.notification_list.lock().unwrap();
-                        for (_, (_, notification)) in map.iter() {
-                            notification.destroy();
-                        }
+                        debug!("Dropping event of replaced idle notification {}", uuid);
+                        continue;
+                    }
+                    if matches!(event, Request::Resumed(..))
+                        && fade
+                            .as_ref()
+                            .is_some_and(|fade| fade.notification == Some(uuid))
+                    {
+                        debug!("Activity, reversing fade");
+                        fade.take().unwrap().reverse(&self.outputs);
                         let _ = self.connection.flush();
                     }
-                    self.tx.send(Request::LuaReload).await.unwrap();
-                }
-                Request::LuaReload => {
-                    debug!("Reloading lua config");
-                    let lua = self.lua.lock().unwrap();
-                    let _ = lua_load_config(&lua).unwrap();
-                }
-                Request::LuaMethod(method_name) => {
-                    let lua = self.lua.lock().unwrap();
-                    let globals = lua.globals();
-                    let map = self.dbus_handlers.lock().unwrap();
-                    match map.get(&method_name) {
-                        Some(fn_name) => {
-                            let fn_name = fn_name.clone();
-                            let result: Result<Function, _> = globals.get(fn_name.clone());
-                            if let Ok(lua_func) = result {
-                                lua_func.call(())?;
-                            } else {
-                                debug!("Lua function not found: {}", fn_name);
-                            }
+                    let Some(runtime) = &runtime else {
+                        continue;
+                    };
+                    let idle = matches!(event, Request::Idled(..));
+                    let timer = runtime.timers().into_iter().find(|timer| timer.id == uuid);
+                    if let Some(idle_hint) = runtime.idle_callback(uuid, idle) {
+                        self.set_idle_hint(idle_hint);
+                    }
+                    if let (Some(conn), Some(timer)) = (&self.control_bus, timer) {
+                        tokio::spawn(control::emit_idle(
+                            conn.clone(),
+                            TimerInfo { idle, ..timer },
+                        ));
+                    }
+                }
+                Request::UpdateIdleHint => {
+                    if let Some(runtime) = &runtime {
+                        self.set_idle_hint(runtime.idle_hint());
+                    }
+                }
+                Request::Event(event) => {
+                    if let Event::OutputAdded(name) = &event {
+                        self.apply_gamma_profile(&runtime, name);
+                        // Catch up with the night light instead of waiting for the next update
+                        if night_light.is_some() {
+                            self.update_night_light(&runtime, &mut night_light);
                         }
-                        None => {
-                            debug!("No dbus handler found for {}", method_name);
+                    }
+                    if let Some(runtime) = &runtime {
+                        runtime.emit(&event);
+                    }
+                    // Timers don't advance during sleep, catch up with the sun right away
+                    if matches!(event, Event::Wakeup) && night_light.is_some() {
+                        self.update_night_light(&runtime, &mut night_light);
+                    }
+                }
+                Request::BlockingEvent(event, done) => {
+                    let tasks = match &runtime {
+                        Some(runtime) => runtime.emit_blocking(&event),
+                        None => Vec::new(),
+                    };
+                    tokio::spawn(async move {
+                        for task in tasks {
+                            let _ = task.await;
                         }
+                        let _ = done.send(());
+                    });
+                }
+                Request::Locked(locked) => {
+                    self.status.lock().unwrap().locked = locked;
+                    self.set_locked_hint(locked);
+                    if let Some(conn) = &self.control_bus {
+                        tokio::spawn(control::emit_locked(conn.clone(), locked));
+                    }
+                    if let Some(runtime) = &runtime {
+                        runtime.emit(&if locked {
+                            Event::Locked
+                        } else {
+                            Event::Unlocked
+                        });
                     }
                 }
+                Request::Session(session) => {
+                    self.status.lock().unwrap().session = Some(session);
+                }
                 Request::OnBattery(state) => {
-                    let lua = self.lua.lock().unwrap();
-                    let globals = lua.globals();
+                    self.status.lock().unwrap().on_battery = state;
+                    let Some(runtime) = &runtime else {
+                        continue;
+                    };
+                    let globals = runtime.lua.globals();
                     let res: mlua::Result<mlua::AnyUserData> = globals.get("Helpers");
 
                     match res {
                         Ok(helpers) => {
-                            let _ = helpers.call_method::<_, bool>("set_on_battery", state);
+                            let _ = runtime
+                                .guarded(|| helpers.call_method::<_, ()>("set_on_battery", state));
                         }
                         Err(_e) => {}
                     }
+                    runtime.emit(&Event::PowerChanged { on_battery: state });
+                }
+                Request::Inhibit {
+                    source,
+                    reason,
+                    duration,
+                } => {
+                    self.inhibit(&mut inhibitor, source, reason, duration);
                 }
-                Request::Inhibit => {
-                    let _ = self.inhibit_sleep();
+                Request::Uninhibit(source) => {
+                    self.uninhibit(&mut inhibitor, &source);
+                }
+                Request::InhibitExpired(source) => {
+                    {
+                        let mut status = self.status.lock().unwrap();
+                        let expired = status.inhibits.get(&source).is_some_and(|inhibit| {
+                            inhibit.until.is_some_and(|until| until <= Instant::now())
+                        });
+                        if expired {
+                            debug!("Inhibit of {} expired", source);
+                            status.inhibits.remove(&source);
+                            self.inhibit_expiries.lock().unwrap().remove(&source);
+                        }
+                    }
+                    self.apply_inhibits(&mut inhibitor);
+                }
+                Request::MediaChanged => {
+                    let playing = self
+                        .media
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .any(MediaPlayer::playing);
+                    if let Some(runtime) = &runtime {
+                        runtime.emit(&Event::MediaChanged { playing });
+                    }
+                    self.update_media_inhibit(&runtime, &mut inhibitor);
+                }
+                Request::MediaInhibit => {
+                    self.update_media_inhibit(&runtime, &mut inhibitor);
+                }
+                Request::NightLight => {
+                    self.update_night_light(&runtime, &mut night_light);
+                }
+                Request::Timezone(timezone) => {
+                    let location = sunset::timezone_location(&timezone);
+                    debug!("Location of timezone {}: {:?}", timezone, location);
+                    self.status.lock().unwrap().location = location;
+                    self.update_night_light(&runtime, &mut night_light);
+                }
+                Request::Fade {
+                    brightness,
+                    duration,
+                    notification,
+                } => {
+                    debug!("Fading to brightness {} in {:?}", brightness, duration);
+                    fade = Some(Fade::start(
+                        fade.take(),
+                        self.outputs.clone(),
+                        brightness,
+                        duration,
+                        notification,
+                        self.tx.clone(),
+                    ));
+                }
+                Request::CancelFade => {
+                    if let Some(fade) = fade.take() {
+                        fade.reverse(&self.outputs);
+                        let _ = self.connection.flush();
+                    }
+                }
+                Request::GetStatus(reply) => {
+                    let _ = reply.send(self.status.lock().unwrap().clone());
+                }
+                Request::ListTimers(reply) => {
+                    let timers = match &runtime {
+                        Some(runtime) => runtime.timers(),
+                        None => Vec::new(),
+                    };
+                    let _ = reply.send(timers);
                 }
                 Request::Flush => {
                     let _ = self.connection.flush();
@@ -440,51 +1684,256 @@ impl WaylandRunner {
         Ok(())
     }
 
-    fn inhibit_sleep(&self) -> anyhow::Result<()> {
-        async fn run(connection: Connection, qhandle: QueueHandle<State>) -> anyhow::Result<()> {
-            // Return early if already inhibited
-            if IS_INHIBITED.load(Ordering::SeqCst) {
-                return Ok(());
+    /// Loads the config into a fresh runtime and only replaces the running one if the config
+    /// was loaded without errors.
+    fn lua_reload(&self, current: &mut Option<LuaRuntime>, env: LuaEnv) {
+        fn load(
+            env: LuaEnv,
+            on_battery: bool,
+            status: StatusHandle,
+            system_bus: zbus::Connection,
+            media: MediaPlayersHandle,
+        ) -> anyhow::Result<LuaRuntime> {
+            let runtime = LuaRuntime::new(env, on_battery, status, system_bus, media)?;
+            if let Err(e) = runtime.load_config() {
+                runtime.teardown();
+                return Err(e);
             }
+            Ok(runtime)
+        }
 
-            debug!("Inhibiting sleep");
-            IS_INHIBITED.store(true, Ordering::SeqCst);
-
-            let mut inhibitor: Option<ZwpIdleInhibitorV1> = None;
-            if let Some(manager) = INHIBIT_MANAGER.lock().unwrap().as_ref() {
-                let surface = SURFACE.lock().unwrap();
-                if let Some(surface) = surface.as_ref() {
-                    inhibitor = Some(manager.create_inhibitor(surface, &qhandle.clone(), ()));
-                    let _ = connection.flush();
+        let on_battery = self.status.lock().unwrap().on_battery;
+        match load(
+            env,
+            on_battery,
+            self.status.clone(),
+            self.system_bus.clone(),
+            self.media.clone(),
+        ) {
+            Ok(runtime) => {
+                if let Some(old) = current.replace(runtime) {
+                    // None of the notifications of the new config are idle yet
+                    let idle_hint = old.idle_hint();
+                    old.teardown();
+                    if idle_hint {
+                        self.set_idle_hint(false);
+                    }
                 }
+                self.status.lock().unwrap().config_error = None;
+                // The new config may inhibit idle for different players or not at all
+                let _ = self.tx.try_send(Request::MediaInhibit);
+                let _ = self.tx.try_send(Request::NightLight);
+                // Fades of the previous config would never be reversed
+                let _ = self.tx.try_send(Request::CancelFade);
             }
-            sleep(Duration::from_secs(config::TIMEOUT_SEC)).await;
+            Err(e) => {
+                error!(
+                    "Error loading config, keeping the previous configuration: {}",
+                    e
+                );
+                self.status.lock().unwrap().config_error = Some(e.to_string());
+                if Args::parse().notify_errors {
+                    tokio::spawn(dbus::notify(
+                        "Failed to load config".to_string(),
+                        e.to_string(),
+                    ));
+                }
+            }
+        }
+        let _ = self.connection.flush();
+    }
 
-            if let Some(inhibitor) = inhibitor {
-                debug!("Destroying inhibitor");
-                inhibitor.destroy();
-                let _ = connection.flush();
+    /// Gives a new output the color of its profile
+    fn apply_gamma_profile(&self, runtime: &Option<LuaRuntime>, name: &str) {
+        let Some(color) = runtime
+            .as_ref()
+            .and_then(|runtime| runtime.gamma_profile(name))
+        else {
+            return;
+        };
+        debug!("Applying gamma profile to {}: {:?}", name, color);
+        for output in self.outputs.lock().unwrap().values_mut() {
+            if output.label() == name {
+                output.set_color(color);
+            }
+        }
+        let _ = self.connection.flush();
+    }
+
+    fn set_session_hint(&self, hint: SessionHint) {
+        let status = self.status.lock().unwrap();
+        match &status.session {
+            Some(session) => {
+                let _ = self.session_hints.send((session.path.clone(), hint));
+            }
+            None => {
+                debug!("No logind session to set {:?} on", hint);
             }
+        }
+    }
 
-            // Reset inhibited state
-            IS_INHIBITED.store(false, Ordering::SeqCst);
+    fn set_idle_hint(&self, idle: bool) {
+        self.set_session_hint(SessionHint::Idle(idle));
+    }
 
-            Ok(())
+    fn set_locked_hint(&self, locked: bool) {
+        self.set_session_hint(SessionHint::Locked(locked));
+    }
+
+    /// Inhibits idle on behalf of `source`, replacing its previous inhibit
+    fn inhibit(
+        &self,
+        inhibitor: &mut Option<ZwpIdleInhibitorV1>,
+        source: String,
+        reason: String,
+        duration: Option<Duration>,
+    ) {
+        debug!("Inhibiting idle for {}: {}", source, reason);
+        // The timer sleeps until the same instant the expiry check compares against, so it
+        // can't fire early and leave the inhibit without a timer
+        let until = duration.map(|duration| Instant::now() + duration);
+        // Renewing an inhibit replaces its timer, so repeated input doesn't pile up timers
+        let expiry = until.map(|until| {
+            let tx = self.tx.clone();
+            let source = source.clone();
+            tokio::spawn(async move {
+                sleep_until(until.into()).await;
+                let _ = tx.send(Request::InhibitExpired(source)).await;
+            })
+        });
+        self.set_inhibit_expiry(&source, expiry);
+        self.status
+            .lock()
+            .unwrap()
+            .inhibits
+            .insert(source, Inhibit { reason, until });
+        self.apply_inhibits(inhibitor);
+    }
+
+    fn uninhibit(&self, inhibitor: &mut Option<ZwpIdleInhibitorV1>, source: &str) {
+        self.set_inhibit_expiry(source, None);
+        let released = self.status.lock().unwrap().inhibits.remove(source);
+        if released.is_some() {
+            debug!("Released inhibit of {}", source);
         }
-        let qh = self.qhandle.clone();
-        let connection = self.connection.clone();
-        tokio::spawn(async move { run(connection, qh).await });
-        Ok(())
+        self.apply_inhibits(inhibitor);
+    }
+
+    fn set_inhibit_expiry(&self, source: &str, expiry: Option<JoinHandle<()>>) {
+        let mut expiries = self.inhibit_expiries.lock().unwrap();
+        let previous = match expiry {
+            Some(expiry) => expiries.insert(source.to_string(), expiry),
+            None => expiries.remove(source),
+        };
+        if let Some(previous) = previous {
+            previous.abort();
+        }
+    }
+
+    /// Inhibits idle while any of the players the config asked for is playing
+    fn update_media_inhibit(
+        &self,
+        runtime: &Option<LuaRuntime>,
+        inhibitor: &mut Option<ZwpIdleInhibitorV1>,
+    ) {
+        let filter = runtime
+            .as_ref()
+            .and_then(|runtime| runtime.media_inhibit.lock().unwrap().clone());
+        let playing = filter.and_then(|filter| {
+            self.media
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(name, player)| player.playing() && player_matches(name, &filter))
+                .map(|(name, _)| dbus::player_name(name).to_string())
+        });
+        match playing {
+            Some(name) => self.inhibit(
+                inhibitor,
+                MEDIA_INHIBIT_SOURCE.to_string(),
+                format!("{} is playing", name),
+                None,
+            ),
+            None => self.uninhibit(inhibitor, MEDIA_INHIBIT_SOURCE),
+        }
+    }
+
+    /// Restarts the night light with the settings of the config, or stops it and restores the
+    /// default color temperature
+    fn update_night_light(
+        &self,
+        runtime: &Option<LuaRuntime>,
+        scheduler: &mut Option<JoinHandle<()>>,
+    ) {
+        let settings = runtime
+            .as_ref()
+            .and_then(|runtime| runtime.night_light.lock().unwrap().clone());
+        let location = settings
+            .as_ref()
+            .and_then(|settings| settings.location)
+            .or(self.status.lock().unwrap().location);
+        let previous = scheduler.take();
+        if let Some(previous) = &previous {
+            previous.abort();
+        }
+        match (settings, location) {
+            (Some(settings), Some(location)) => {
+                debug!("Night light at {:?}", location);
+                *scheduler = Some(tokio::spawn(sunset::night_light_scheduler(
+                    settings,
+                    location,
+                    self.outputs.clone(),
+                    self.tx.clone(),
+                )));
+            }
+            (settings, _) => {
+                if settings.is_some() {
+                    warn!("The night light needs a location, set latitude and longitude");
+                }
+                if previous.is_some() {
+                    debug!("Night light disabled");
+                    for output in self.outputs.lock().unwrap().values_mut() {
+                        output.set_temp(Color::default().temp);
+                    }
+                    let _ = self.connection.flush();
+                }
+            }
+        }
+    }
+
+    /// Creates the Wayland idle inhibitor while any inhibit is active and destroys it once the
+    /// last one is released.
+    fn apply_inhibits(&self, inhibitor: &mut Option<ZwpIdleInhibitorV1>) {
+        let inhibited = !self.status.lock().unwrap().inhibits.is_empty();
+        match (inhibited, inhibitor.take()) {
+            (true, None) => {
+                if let Some(manager) = INHIBIT_MANAGER.lock().unwrap().as_ref() {
+                    let surface = SURFACE.lock().unwrap();
+                    if let Some(surface) = surface.as_ref() {
+                        debug!("Creating idle inhibitor");
+                        *inhibitor = Some(manager.create_inhibitor(surface, &self.qhandle, ()));
+                    }
+                }
+            }
+            (false, Some(current)) => {
+                debug!("Destroying idle inhibitor");
+                current.destroy();
+            }
+            (_, current) => *inhibitor = current,
+        }
+        let _ = self.connection.flush();
     }
 }
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     Builder::from_env(Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+    if let Some(Subcommands::Ctl { json, command }) = args.command {
+        return control::ctl(command, json).await;
+    }
     let _ = ensure_config_file_exists(config::CONFIG_FILE_NAME);
     let (tx, mut rx) = mpsc::channel(32);
 
-    let lua = Arc::new(Mutex::new(Lua::new()));
-
     let config_path = utils::xdg_config_path(None)?;
     filewatcher_run(&config_path, tx.clone())
         .await
@@ -494,17 +1943,114 @@ async fn main() -> anyhow::Result<()> {
     let event_queue: EventQueue<State> = connection.new_event_queue();
     let qhandle = event_queue.handle();
 
-    let wayland_runner = WaylandRunner::new(connection, qhandle.clone(), lua.clone(), tx.clone());
+    let system_bus = zbus::Connection::system().await?;
+    let media: MediaPlayersHandle = Arc::new(Mutex::new(HashMap::new()));
+    {
+        let (tx, media) = (tx.clone(), media.clone());
+        tokio::spawn(async move {
+            if let Err(e) = dbus::mpris_watcher(tx, media).await {
+                warn!("Failed to watch media players: {}", e);
+            }
+        });
+    }
+    if let Some(timezone) = sunset::system_timezone() {
+        let _ = tx.send(Request::Timezone(timezone)).await;
+    }
+    if let Err(e) = dbus::timedate_watcher(system_bus.clone(), tx.clone()).await {
+        warn!("Failed to watch the timezone: {}", e);
+    }
+    let control_bus = match control::serve(tx.clone()).await {
+        Ok(conn) => Some(conn),
+        Err(e) => {
+            warn!("Failed to serve the control interface: {}", e);
+            None
+        }
+    };
+    // Kept alive for as long as the daemon runs
+    let _screensaver_bus = if args.screensaver {
+        match screensaver::serve(tx.clone()).await {
+            Ok(conn) => Some(conn),
+            Err(e) => {
+                warn!("Failed to serve org.freedesktop.ScreenSaver: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let wayland_runner = WaylandRunner::new(
+        connection,
+        qhandle.clone(),
+        tx.clone(),
+        system_bus.clone(),
+        control_bus,
+        media,
+    );
     let udev_handler = UdevHandler::new(tx.clone());
 
     let _ = wayland_runner.wayland_run(event_queue).await;
 
     tokio::try_join!(
-        dbus::upower_watcher(tx.clone()),
-        dbus::logind_watcher(tx.clone()),
+        dbus::upower_watcher(system_bus.clone(), tx.clone()),
+        dbus::logind_watcher(
+            system_bus,
+            tx.clone(),
+            Duration::from_millis(args.max_sleep_delay)
+        ),
         wayland_runner.process_command(&mut rx),
         udev_handler.monitor()
     )?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(players: &[&str]) -> Vec<String> {
+        players.iter().map(|player| player.to_string()).collect()
+    }
+
+    #[test]
+    fn empty_filter_matches_every_player() {
+        assert!(player_matches("org.mpris.MediaPlayer2.mpv", &[]));
+        assert!(player_matches(
+            "org.mpris.MediaPlayer2.firefox.instance_1_42",
+            &[]
+        ));
+    }
+
+    #[test]
+    fn players_match_by_name() {
+        let players = filter(&["mpv", "firefox"]);
+        assert!(player_matches("org.mpris.MediaPlayer2.mpv", &players));
+        assert!(player_matches("org.mpris.MediaPlayer2.firefox", &players));
+        assert!(!player_matches("org.mpris.MediaPlayer2.spotify", &players));
+    }
+
+    #[test]
+    fn instances_match_without_suffix() {
+        let players = filter(&["firefox"]);
+        assert!(player_matches(
+            "org.mpris.MediaPlayer2.firefox.instance_1_42",
+            &players
+        ));
+        assert!(player_matches(
+            "org.mpris.MediaPlayer2.firefox.instance_1_42",
+            &filter(&["firefox.instance_1_42"])
+        ));
+    }
+
+    #[test]
+    fn prefixes_of_a_name_dont_match() {
+        assert!(!player_matches(
+            "org.mpris.MediaPlayer2.firefox",
+            &filter(&["fire"])
+        ));
+        assert!(!player_matches(
+            "org.mpris.MediaPlayer2.mpv2",
+            &filter(&["mpv"])
+        ));
+    }
+}
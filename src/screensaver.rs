@@ -0,0 +1,157 @@
+use super::types::Request;
+use futures::stream::StreamExt;
+use log::{debug, error};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{mpsc, oneshot};
+use zbus::{dbus_interface, fdo, Connection, ConnectionBuilder, MessageHeader};
+
+const BUS_NAME: &str = "org.freedesktop.ScreenSaver";
+/// Applications use either path
+const OBJECT_PATHS: [&str; 2] = ["/org/freedesktop/ScreenSaver", "/ScreenSaver"];
+
+#[derive(Debug, Default)]
+struct Cookies {
+    next: u32,
+    /// Unique bus name of the caller by cookie
+    owners: HashMap<u32, String>,
+}
+
+type CookiesHandle = Arc<Mutex<Cookies>>;
+
+fn inhibit_source(cookie: u32) -> String {
+    format!("screensaver:{}", cookie)
+}
+
+/// `org.freedesktop.ScreenSaver` as used by browsers and video players to inhibit idle
+#[derive(Clone, Debug)]
+struct ScreenSaverInterface {
+    tx: mpsc::Sender<Request>,
+    cookies: CookiesHandle,
+}
+
+impl ScreenSaverInterface {
+    async fn send(&self, request: Request) -> fdo::Result<()> {
+        self.tx
+            .send(request)
+            .await
+            .map_err(|_| fdo::Error::Failed("The daemon is shutting down".to_string()))
+    }
+}
+
+#[dbus_interface(name = "org.freedesktop.ScreenSaver")]
+impl ScreenSaverInterface {
+    async fn inhibit(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        application_name: String,
+        reason_for_inhibit: String,
+    ) -> fdo::Result<u32> {
+        let sender = header
+            .sender()?
+            .map(|sender| sender.to_string())
+            .unwrap_or_default();
+        let cookie = {
+            let mut cookies = self.cookies.lock().unwrap();
+            cookies.next = cookies.next.wrapping_add(1).max(1);
+            let cookie = cookies.next;
+            cookies.owners.insert(cookie, sender.clone());
+            cookie
+        };
+        debug!(
+            "{} ({}) inhibits the screensaver: {}",
+            application_name, sender, reason_for_inhibit
+        );
+        self.send(Request::Inhibit {
+            source: inhibit_source(cookie),
+            reason: format!("{}: {}", application_name, reason_for_inhibit),
+            duration: None,
+        })
+        .await?;
+        Ok(cookie)
+    }
+
+    #[dbus_interface(name = "UnInhibit")]
+    async fn uninhibit(&self, cookie: u32) -> fdo::Result<()> {
+        let owner = self.cookies.lock().unwrap().owners.remove(&cookie);
+        if owner.is_none() {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Unknown cookie {}",
+                cookie
+            )));
+        }
+        self.send(Request::Uninhibit(inhibit_source(cookie))).await
+    }
+
+    /// The screensaver counts as active while the screen is locked
+    async fn get_active(&self) -> fdo::Result<bool> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(Request::GetStatus(reply_tx)).await?;
+        let status = reply_rx
+            .await
+            .map_err(|_| fdo::Error::Failed("The request was dropped".to_string()))?;
+        Ok(status.locked)
+    }
+}
+
+/// Releases the inhibits of callers that disconnected without calling `UnInhibit`
+async fn release_vanished(
+    conn: Connection,
+    tx: mpsc::Sender<Request>,
+    cookies: CookiesHandle,
+) -> anyhow::Result<()> {
+    let dbus_proxy = fdo::DBusProxy::new(&conn).await?;
+    let mut name_owner_changed = dbus_proxy.receive_name_owner_changed().await?;
+
+    tokio::spawn(async move {
+        while let Some(signal) = name_owner_changed.next().await {
+            let args = match signal.args() {
+                Ok(args) => args,
+                Err(e) => {
+                    error!("Error getting name_owner_changed args: {}", e);
+                    continue;
+                }
+            };
+            if args.new_owner().is_some() {
+                continue;
+            }
+            let name = args.name().to_string();
+            let released: Vec<u32> = {
+                let mut cookies = cookies.lock().unwrap();
+                let released = cookies
+                    .owners
+                    .iter()
+                    .filter(|(_, owner)| **owner == name)
+                    .map(|(cookie, _)| *cookie)
+                    .collect::<Vec<_>>();
+                for cookie in &released {
+                    cookies.owners.remove(cookie);
+                }
+                released
+            };
+            for cookie in released {
+                debug!("{} disconnected, releasing cookie {}", name, cookie);
+                let _ = tx.send(Request::Uninhibit(inhibit_source(cookie))).await;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Claims `org.freedesktop.ScreenSaver` on the session bus and turns its inhibits into Wayland
+/// idle inhibits
+pub async fn serve(tx: mpsc::Sender<Request>) -> anyhow::Result<Connection> {
+    let interface = ScreenSaverInterface {
+        tx: tx.clone(),
+        cookies: Arc::new(Mutex::new(Cookies::default())),
+    };
+    let mut builder = ConnectionBuilder::session()?.name(BUS_NAME)?;
+    for path in OBJECT_PATHS {
+        builder = builder.serve_at(path, interface.clone())?;
+    }
+    let conn = builder.build().await?;
+    release_vanished(conn.clone(), tx, interface.cookies).await?;
+    Ok(conn)
+}
@@ -0,0 +1,351 @@
+//! Position of the sun, following the NOAA solar calculator
+//! <https://gml.noaa.gov/grad/solcalc/calcdetails.html>. Everything is computed offline from
+//! the location and the time.
+
+use log::{debug, warn};
+use std::{
+    env, fs,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, time::sleep};
+
+use crate::{
+    config,
+    types::{OutputListHandle, Request},
+};
+
+/// Elevation of the sun at sunrise and sunset, corrected for refraction and the size of the sun
+const SUNRISE_ELEVATION: f64 = -0.833;
+/// Elevation of the sun at the start of dawn and the end of dusk (civil twilight)
+const TWILIGHT_ELEVATION: f64 = -6.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    /// Degrees north
+    pub latitude: f64,
+    /// Degrees east
+    pub longitude: f64,
+}
+
+/// Parses an ISO 6709 coordinate like `+4230` or `-0651130`
+fn parse_coordinate(value: &str, degree_digits: usize) -> Option<f64> {
+    let sign = match value.get(..1)? {
+        "+" => 1.0,
+        "-" => -1.0,
+        _ => return None,
+    };
+    let digits = &value[1..];
+    if !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let degrees: f64 = digits.get(..degree_digits)?.parse().ok()?;
+    let minutes: f64 = digits.get(degree_digits..degree_digits + 2)?.parse().ok()?;
+    let seconds: f64 = match digits.get(degree_digits + 2..) {
+        Some("") | None => 0.0,
+        Some(seconds) => seconds.parse().ok()?,
+    };
+    Some(sign * (degrees + minutes / 60.0 + seconds / 3600.0))
+}
+
+/// Parses the coordinates column of `zone1970.tab`, e.g. `+5230+01322`
+fn parse_coordinates(value: &str) -> Option<Location> {
+    let split = value.get(1..)?.find(['+', '-'])? + 1;
+    Some(Location {
+        latitude: parse_coordinate(&value[..split], 2)?,
+        longitude: parse_coordinate(&value[split..], 3)?,
+    })
+}
+
+/// Looks up the location of the principal city of a timezone like `Europe/Berlin` in the
+/// tzdata tables
+pub fn timezone_location(timezone: &str) -> Option<Location> {
+    let tzdir = env::var("TZDIR").unwrap_or_else(|_| "/usr/share/zoneinfo".to_string());
+    for table in ["zone1970.tab", "zone.tab"] {
+        let Ok(table) = fs::read_to_string(Path::new(&tzdir).join(table)) else {
+            continue;
+        };
+        let coordinates = table
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.split('\t').collect::<Vec<_>>())
+            .find(|columns| columns.get(2) == Some(&timezone))
+            .and_then(|columns| parse_coordinates(columns[1]));
+        if coordinates.is_some() {
+            return coordinates;
+        }
+    }
+    warn!("No location found for timezone {}", timezone);
+    None
+}
+
+/// Name of the local timezone from `TZ` or `/etc/localtime`
+pub fn system_timezone() -> Option<String> {
+    if let Ok(timezone) = env::var("TZ") {
+        return Some(timezone.trim_start_matches(':').to_string());
+    }
+    let target = fs::read_link("/etc/localtime").ok()?;
+    let target = target.to_str()?;
+    let (_, timezone) = target.split_once("zoneinfo/")?;
+    Some(timezone.to_string())
+}
+
+/// Unix time in seconds
+pub fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs_f64())
+        .unwrap_or_default()
+}
+
+struct SolarPosition {
+    /// Declination of the sun in radians
+    declination: f64,
+    /// Equation of time in minutes
+    equation_of_time: f64,
+}
+
+fn solar_position(time: f64) -> SolarPosition {
+    let julian_day = time / 86400.0 + 2440587.5;
+    let t = (julian_day - 2451545.0) / 36525.0;
+
+    let mean_longitude = (280.46646 + t * (36000.76983 + t * 0.0003032)).rem_euclid(360.0);
+    let mean_anomaly = 357.52911 + t * (35999.05029 - 0.0001537 * t);
+    let eccentricity = 0.016708634 - t * (0.000042037 + 0.0000001267 * t);
+    let m = mean_anomaly.to_radians();
+    let center = m.sin() * (1.914602 - t * (0.004817 + 0.000014 * t))
+        + (2.0 * m).sin() * (0.019993 - 0.000101 * t)
+        + (3.0 * m).sin() * 0.000289;
+    let omega = (125.04 - 1934.136 * t).to_radians();
+    let apparent_longitude =
+        (mean_longitude + center - 0.00569 - 0.00478 * omega.sin()).to_radians();
+    let mean_obliquity =
+        23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60.0) / 60.0;
+    let obliquity = (mean_obliquity + 0.00256 * omega.cos()).to_radians();
+
+    let declination = (obliquity.sin() * apparent_longitude.sin()).asin();
+    let y = (obliquity / 2.0).tan().powi(2);
+    let l0 = mean_longitude.to_radians();
+    let equation_of_time = 4.0
+        * (y * (2.0 * l0).sin() - 2.0 * eccentricity * m.sin()
+            + 4.0 * eccentricity * y * m.sin() * (2.0 * l0).cos()
+            - 0.5 * y * y * (4.0 * l0).sin()
+            - 1.25 * eccentricity * eccentricity * (2.0 * m).sin())
+        .to_degrees();
+
+    SolarPosition {
+        declination,
+        equation_of_time,
+    }
+}
+
+/// Elevation of the sun above the horizon in degrees, not corrected for refraction
+pub fn elevation(location: Location, time: f64) -> f64 {
+    let position = solar_position(time);
+    let minutes = (time / 60.0).rem_euclid(1440.0);
+    let true_solar_time = minutes + position.equation_of_time + 4.0 * location.longitude;
+    let hour_angle = (true_solar_time / 4.0 - 180.0).to_radians();
+    let latitude = location.latitude.to_radians();
+
+    let cos_zenith = latitude.sin() * position.declination.sin()
+        + latitude.cos() * position.declination.cos() * hour_angle.cos();
+    90.0 - cos_zenith.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+/// Times the sun passes an elevation on one day, in Unix time. `None` if it stays above or
+/// below the elevation all day, like during polar day or night.
+fn crossing(location: Location, noon: f64, elevation: f64) -> Option<(f64, f64)> {
+    let declination = solar_position(noon).declination;
+    let latitude = location.latitude.to_radians();
+    let cos_hour_angle = (elevation.to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    // The sun moves by one degree of hour angle in four minutes
+    let offset = cos_hour_angle.acos().to_degrees() * 4.0 * 60.0;
+    Some((noon - offset, noon + offset))
+}
+
+/// Sunrise, sunset and civil twilight of one day, in Unix time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SunTimes {
+    pub dawn: Option<f64>,
+    pub sunrise: Option<f64>,
+    pub noon: f64,
+    pub sunset: Option<f64>,
+    pub dusk: Option<f64>,
+}
+
+/// Computes the sun times of the solar day `time` falls on at `location`
+pub fn sun_times(location: Location, time: f64) -> SunTimes {
+    // Local solar days start at local midnight, not at midnight UTC
+    let local_day = ((time + location.longitude * 240.0) / 86400.0).floor();
+    let midnight = local_day * 86400.0;
+    let approx_noon = midnight + (720.0 - 4.0 * location.longitude) * 60.0;
+    let noon = approx_noon - solar_position(approx_noon).equation_of_time * 60.0;
+
+    let (sunrise, sunset) = crossing(location, noon, SUNRISE_ELEVATION).unzip();
+    let (dawn, dusk) = crossing(location, noon, TWILIGHT_ELEVATION).unzip();
+    SunTimes {
+        dawn,
+        sunrise,
+        noon,
+        sunset,
+        dusk,
+    }
+}
+
+/// Night light settings from the Lua config
+#[derive(Clone, Debug)]
+pub struct NightLight {
+    /// Overrides the location of the timezone
+    pub location: Option<Location>,
+    pub day_temp: u16,
+    pub night_temp: u16,
+    /// Solar elevation in degrees from which on the day temperature is used
+    pub high: f64,
+    /// Solar elevation in degrees below which the night temperature is used
+    pub low: f64,
+}
+
+impl NightLight {
+    /// Color temperature for a point in time. It changes gradually while the sun is between
+    /// `low` and `high`.
+    pub fn temperature(&self, location: Location, time: f64) -> u16 {
+        let elevation = elevation(location, time);
+        let day = ((elevation - self.low) / (self.high - self.low)).clamp(0.0, 1.0);
+        let (day_temp, night_temp) = (f64::from(self.day_temp), f64::from(self.night_temp));
+        (night_temp + (day_temp - night_temp) * day).round() as u16
+    }
+}
+
+/// Keeps the color temperature of all outputs in line with the position of the sun
+pub async fn night_light_scheduler(
+    night_light: NightLight,
+    location: Location,
+    outputs: OutputListHandle,
+    tx: mpsc::Sender<Request>,
+) {
+    loop {
+        let temp = night_light.temperature(location, now());
+        debug!("Night light temperature: {}K", temp);
+        for output in outputs.lock().unwrap().values_mut() {
+            output.set_temp(temp);
+        }
+        let _ = tx.send(Request::Flush).await;
+        sleep(Duration::from_secs(config::NIGHT_LIGHT_INTERVAL_SEC)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-06-21 00:00 UTC, the June solstice
+    const SOLSTICE: f64 = 1_718_928_000.0;
+    const HOUR: f64 = 3600.0;
+    /// Published sunrise and sunset times are rounded to the minute
+    const TOLERANCE: f64 = 120.0;
+
+    const BERLIN: Location = Location {
+        latitude: 52.52,
+        longitude: 13.405,
+    };
+    const SYDNEY: Location = Location {
+        latitude: -33.8688,
+        longitude: 151.2093,
+    };
+    const TROMSO: Location = Location {
+        latitude: 69.6496,
+        longitude: 18.956,
+    };
+
+    fn assert_near(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("the sun should rise and set");
+        assert!(
+            (actual - expected).abs() <= TOLERANCE,
+            "expected {} ± {}s, got {}",
+            expected,
+            TOLERANCE,
+            actual
+        );
+    }
+
+    fn assert_location(location: Option<Location>, latitude: f64, longitude: f64) {
+        let location = location.expect("the coordinates should parse");
+        assert!((location.latitude - latitude).abs() < 1e-4);
+        assert!((location.longitude - longitude).abs() < 1e-4);
+    }
+
+    #[test]
+    fn coordinates_in_minutes() {
+        assert_location(parse_coordinates("+5230+01322"), 52.5, 13.3667);
+        assert_location(parse_coordinates("-3352+15113"), -33.8667, 151.2167);
+    }
+
+    #[test]
+    fn coordinates_in_seconds() {
+        assert_location(parse_coordinates("+404251-0740023"), 40.7142, -74.0064);
+        let longitude = parse_coordinate("-0651130", 3).unwrap();
+        assert!((longitude + 65.1917).abs() < 1e-4);
+    }
+
+    #[test]
+    fn malformed_coordinates() {
+        for value in [
+            "",
+            "+",
+            "+52",
+            "+5230",
+            "5230+01322",
+            "+5230 01322",
+            "+52a0+01322",
+            "+52-0+01322",
+            "+5230+013",
+        ] {
+            assert!(parse_coordinates(value).is_none(), "{:?} parsed", value);
+        }
+        assert!(parse_coordinate("+0651", 3).is_none());
+        assert!(parse_coordinate("*0651130", 3).is_none());
+    }
+
+    #[test]
+    fn berlin_solstice() {
+        let times = sun_times(BERLIN, SOLSTICE + 12.0 * HOUR);
+        // 04:43 and 21:33 CEST
+        assert_near(times.sunrise, SOLSTICE + 2.0 * HOUR + 43.0 * 60.0);
+        assert_near(times.sunset, SOLSTICE + 19.0 * HOUR + 33.0 * 60.0);
+        assert!(times.dawn.unwrap() < times.sunrise.unwrap());
+        assert!(times.dusk.unwrap() > times.sunset.unwrap());
+    }
+
+    #[test]
+    fn berlin_noon_elevation() {
+        let times = sun_times(BERLIN, SOLSTICE + 12.0 * HOUR);
+        // 90° - latitude + axial tilt
+        let expected = 90.0 - BERLIN.latitude + 23.44;
+        assert!((elevation(BERLIN, times.noon) - expected).abs() < 0.1);
+    }
+
+    #[test]
+    fn sydney_local_day() {
+        // 12:00 AEST on June 21, the sun rose at 07:00 AEST, still June 20 in UTC
+        let times = sun_times(SYDNEY, SOLSTICE + 2.0 * HOUR);
+        assert_near(times.sunrise, SOLSTICE - 3.0 * HOUR);
+        assert_near(times.sunset, SOLSTICE + 6.0 * HOUR + 54.0 * 60.0);
+
+        // 06:00 AEST on June 22 is already the next local day
+        let times = sun_times(SYDNEY, SOLSTICE + 20.0 * HOUR);
+        assert_near(times.sunrise, SOLSTICE + 21.0 * HOUR);
+    }
+
+    #[test]
+    fn tromso_polar_day() {
+        let times = sun_times(TROMSO, SOLSTICE + 12.0 * HOUR);
+        assert!(times.sunrise.is_none());
+        assert!(times.sunset.is_none());
+        assert!(times.dawn.is_none());
+        assert!(elevation(TROMSO, SOLSTICE) > 0.0);
+    }
+}
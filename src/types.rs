@@ -1,42 +1,287 @@
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
 };
-use tokio::sync::mpsc;
 
-use mlua::Lua;
+use mlua::RegistryKey;
 use uuid::Uuid;
 use wayland_client::{protocol::wl_seat, QueueHandle};
 use wayland_protocols::ext::idle_notify::v1::client::{
     ext_idle_notification_v1, ext_idle_notifier_v1,
 };
+use wayland_protocols_wlr::gamma_control::v1::client::zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1;
+use wayland_protocols_wlr::output_power_management::v1::client::zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1;
 
-use crate::wayland::Output;
+use crate::{
+    color::Color,
+    dbus::MediaPlayer2PlayerProxy,
+    sunset::{Location, NightLight},
+    wayland::Output,
+};
 
 #[derive(Debug)]
 pub enum Request {
-    LuaReload,
-    LuaMethod(String),
+    LuaInit(LuaEnv),
+    Event(Event),
+    /// Like `Event`, but the sender is notified once all handlers have finished
+    BlockingEvent(Event, oneshot::Sender<()>),
+    Session(Session),
+    /// A locker started with `lock` started or exited
+    Locked(bool),
+    /// An idle notification and the generation of its Wayland object
+    Idled(Uuid, u32),
+    Resumed(Uuid, u32),
+    /// An idle notification used for the idle hint was cancelled or changed while idle
+    UpdateIdleHint,
     Reset,
     OnBattery(bool),
     Flush,
-    Inhibit,
+    /// Inhibits idle on behalf of `source`, replacing a previous inhibit of the same source.
+    /// Without a duration the inhibit lasts until `Uninhibit`.
+    Inhibit {
+        source: String,
+        reason: String,
+        duration: Option<Duration>,
+    },
+    Uninhibit(String),
+    /// The duration of an inhibit passed, it's released unless it was renewed in the meantime
+    InhibitExpired(String),
+    GetStatus(oneshot::Sender<Status>),
+    /// An MPRIS player appeared, vanished or changed its playback status
+    MediaChanged,
+    /// The players idle is inhibited for changed
+    MediaInhibit,
+    /// The night light settings changed
+    NightLight,
+    /// The system timezone is known or changed
+    Timezone(String),
+    /// Fades the brightness of all outputs, reversed when `notification` resumes
+    Fade {
+        brightness: f64,
+        duration: Duration,
+        notification: Option<Uuid>,
+    },
+    /// Stops the fade and restores the brightness
+    CancelFade,
+    ListTimers(oneshot::Sender<Vec<TimerInfo>>),
+}
+
+#[derive(Debug)]
+pub struct NotificationEntry {
+    pub callback: RegistryKey,
+    pub notification: ext_idle_notification_v1::ExtIdleNotificationV1,
+    /// Counts replacements of `notification`, events of replaced objects are dropped
+    pub generation: u32,
+    /// Timeout in seconds
+    pub timeout: u32,
+    pub idle: bool,
+    /// Whether logind's idle hint follows this notification
+    pub idle_hint: bool,
+}
+
+pub type NotificationListHandle = Arc<Mutex<HashMap<Uuid, NotificationEntry>>>;
+
+/// An idle notification of the running config
+#[derive(Clone, Debug)]
+pub struct TimerInfo {
+    pub id: Uuid,
+    /// Timeout in seconds
+    pub timeout: u32,
+    pub idle: bool,
+    pub idle_hint: bool,
+}
+
+/// Events Lua handlers can subscribe to with `Events:on(name, handler)`
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// logind is about to suspend or hibernate
+    PrepareSleep,
+    /// The system resumed from sleep
+    Wakeup,
+    /// logind asked the session to lock, e.g. `loginctl lock-session`
+    Lock,
+    /// logind asked the session to unlock
+    Unlock,
+    /// A locker started with `IdleNotifier:lock` is running
+    Locked,
+    /// The locker started with `IdleNotifier:lock` exited
+    Unlocked,
+    /// The power supply changed, handlers receive whether the system runs on battery
+    PowerChanged { on_battery: bool },
+    /// A joystick was connected, handlers receive the device name
+    DeviceAdded(String),
+    /// A joystick was disconnected, handlers receive the device name
+    DeviceRemoved(String),
+    /// A media player started or stopped playing, handlers receive whether any player plays
+    MediaChanged { playing: bool },
+    /// An output was connected, handlers receive its name
+    OutputAdded(String),
+    /// An output was disconnected, handlers receive its name
+    OutputRemoved(String),
 }
 
-pub type NotificationListHandle =
-    Arc<Mutex<HashMap<Uuid, (String, ext_idle_notification_v1::ExtIdleNotificationV1)>>>;
+impl Event {
+    /// Names of all events, as used from Lua
+    pub const NAMES: &'static [&'static str] = &[
+        "prepare_sleep",
+        "wakeup",
+        "lock",
+        "unlock",
+        "locked",
+        "unlocked",
+        "power_changed",
+        "device_added",
+        "device_removed",
+        "media_changed",
+        "output_added",
+        "output_removed",
+    ];
+
+    /// Events without arguments, which can be triggered from outside
+    pub const TRIGGERABLE: &'static [Event] = &[
+        Event::PrepareSleep,
+        Event::Wakeup,
+        Event::Lock,
+        Event::Unlock,
+        Event::Locked,
+        Event::Unlocked,
+    ];
+
+    /// Parses the name of an event that has no arguments, used to trigger events from outside
+    pub fn from_name(name: &str) -> Option<Event> {
+        Event::TRIGGERABLE
+            .iter()
+            .find(|event| event.name() == name)
+            .cloned()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::PrepareSleep => "prepare_sleep",
+            Event::Wakeup => "wakeup",
+            Event::Lock => "lock",
+            Event::Unlock => "unlock",
+            Event::Locked => "locked",
+            Event::Unlocked => "unlocked",
+            Event::PowerChanged { .. } => "power_changed",
+            Event::DeviceAdded(_) => "device_added",
+            Event::DeviceRemoved(_) => "device_removed",
+            Event::MediaChanged { .. } => "media_changed",
+            Event::OutputAdded(_) => "output_added",
+            Event::OutputRemoved(_) => "output_removed",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EventCallback {
+    pub id: Uuid,
+    /// Handlers with a higher priority run first
+    pub priority: i32,
+    pub callback: RegistryKey,
+}
+
+/// Commands started with `run_wait` while a blocking event is handled, `None` otherwise
+pub type BlockingTaskListHandle = Arc<Mutex<Option<Vec<JoinHandle<()>>>>>;
+
+/// Handlers per event name, kept sorted in the order they are called
+pub type CallbackListHandle = Arc<Mutex<HashMap<String, Vec<EventCallback>>>>;
+/// An MPRIS player on the session bus
+#[derive(Clone, Debug)]
+pub struct MediaPlayer {
+    /// `PlaybackStatus` of the player, "Playing", "Paused" or "Stopped"
+    pub status: String,
+    pub proxy: MediaPlayer2PlayerProxy<'static>,
+}
+
+impl MediaPlayer {
+    pub fn playing(&self) -> bool {
+        self.status == "Playing"
+    }
+}
+
+/// MPRIS players by bus name
+pub type MediaPlayersHandle = Arc<Mutex<HashMap<String, MediaPlayer>>>;
+
+/// Players idle is inhibited for while they play. `None` if disabled, empty for all players.
+pub type MediaInhibitHandle = Arc<Mutex<Option<Vec<String>>>>;
+
+/// Night light settings of the config, `None` if it's disabled
+pub type NightLightHandle = Arc<Mutex<Option<NightLight>>>;
+
+/// Colors of the config by output name or glob, later profiles take precedence
+pub type GammaProfilesHandle = Arc<Mutex<Vec<(String, Color)>>>;
+
+/// The logind session sleepwatcher-rs belongs to
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub id: String,
+    /// D-Bus object path of the session
+    pub path: String,
+}
+
+/// Keeps the session from going idle
+#[derive(Clone, Debug)]
+pub struct Inhibit {
+    pub reason: String,
+    /// `None` if the inhibit lasts until it's released
+    pub until: Option<Instant>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Status {
+    /// Error of the last config load, cleared once a config loads successfully
+    pub config_error: Option<String>,
+    pub session: Option<Session>,
+    /// Whether a locker started with `lock` is running
+    pub locked: bool,
+    pub on_battery: bool,
+    /// Active inhibits by source, idle is inhibited while there is any
+    pub inhibits: HashMap<String, Inhibit>,
+    /// Approximate location of the system timezone
+    pub location: Option<Location>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            config_error: None,
+            session: None,
+            locked: false,
+            on_battery: true,
+            inhibits: HashMap::new(),
+            location: None,
+        }
+    }
+}
+
+pub type StatusHandle = Arc<Mutex<Status>>;
+
+/// Wayland globals and channels the Lua API is created with
+#[derive(Clone, Debug)]
+pub struct LuaEnv {
+    pub(crate) wl_seat: wl_seat::WlSeat,
+    pub(crate) idle_notifier: ext_idle_notifier_v1::ExtIdleNotifierV1,
+    pub(crate) qh: QueueHandle<State>,
+    pub(crate) tx: mpsc::Sender<Request>,
+    pub(crate) outputs: OutputListHandle,
+}
 
-pub type CallbackListHandle = Arc<Mutex<HashMap<String, String>>>;
-pub type LuaHandle = Arc<Mutex<Lua>>;
+/// Outputs by their registry name
+pub type OutputListHandle = Arc<Mutex<HashMap<u32, Output>>>;
 
 #[derive(Debug)]
 pub struct State {
     pub(crate) wl_seat: Option<wl_seat::WlSeat>,
     pub(crate) qh: QueueHandle<State>,
     pub(crate) idle_notifier: Option<ext_idle_notifier_v1::ExtIdleNotifierV1>,
-    pub(crate) notification_list: NotificationListHandle,
-    pub(crate) dbus_handlers: CallbackListHandle,
     pub(crate) tx: mpsc::Sender<Request>,
-    pub(crate) lua: LuaHandle,
-    pub(crate) outputs: HashMap<u32, Output>,
+    pub(crate) outputs: OutputListHandle,
+    pub(crate) gamma_manager: Option<ZwlrGammaControlManagerV1>,
+    pub(crate) power_manager: Option<ZwlrOutputPowerManagerV1>,
 }
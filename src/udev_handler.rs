@@ -8,7 +8,7 @@ use tokio::{
 use udev::{EventType, MonitorBuilder};
 
 use crate::joystick_handler::JoystickHandler;
-use crate::types::Request;
+use crate::types::{Event, Request};
 
 pub struct UdevHandler {
     joysticks: Mutex<HashMap<String, JoinHandle<anyhow::Result<()>>>>,
@@ -27,6 +27,10 @@ impl UdevHandler {
         if let Some(sysname) = self.get_joystick_sysname(joystick) {
             debug!("Added joystick {}", sysname.clone());
             let joystick_handler = JoystickHandler::new(sysname.clone(), self.tx.clone());
+            let _ = self
+                .tx
+                .send(Request::Event(Event::DeviceAdded(sysname.clone())))
+                .await;
             let mut joysticks = self.joysticks.lock().await;
             joysticks.insert(
                 sysname.clone(),
@@ -43,6 +47,10 @@ impl UdevHandler {
             let task = joysticks.get(&sysname).unwrap();
             task.abort();
             joysticks.remove(&sysname);
+            let _ = self
+                .tx
+                .send(Request::Event(Event::DeviceRemoved(sysname)))
+                .await;
         }
         Ok(())
     }
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 use xdg::BaseDirectories;
 
 use super::config;
@@ -21,3 +21,120 @@ pub fn xdg_config_path(filename: Option<String>) -> std::io::Result<PathBuf> {
         None => Ok(xdg_dirs.get_config_home()),
     }
 }
+
+/// Parses durations like `45m`, `1h30m`, `90s` or `90`, plain numbers are seconds
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let mut seconds = 0u64;
+    let mut number = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(format!("Invalid unit '{}' in duration {}", c, value)),
+        };
+        let n: u64 = number
+            .parse()
+            .map_err(|_| format!("Missing number before '{}' in duration {}", c, value))?;
+        seconds = n
+            .checked_mul(unit)
+            .and_then(|n| seconds.checked_add(n))
+            .ok_or_else(|| format!("Duration {} is too long", value))?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        let n = number
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid duration {}: {}", value, e))?;
+        seconds = seconds
+            .checked_add(n)
+            .ok_or_else(|| format!("Duration {} is too long", value))?;
+    }
+    if seconds == 0 {
+        return Err(format!("Invalid duration {}", value));
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Formats seconds like `44m 10s`
+pub fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", seconds),
+        (0, _) => format!("{}m {}s", minutes, seconds),
+        _ => format!("{}h {}m", hours, minutes),
+    }
+}
+
+/// Matches shell style patterns with `*` and `?` like `DP-*`
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text it matched up to, to backtrack to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_literal() {
+        assert!(glob_match("DP-1", "DP-1"));
+        assert!(!glob_match("DP-1", "DP-2"));
+        assert!(!glob_match("DP-1", "DP-10"));
+    }
+
+    #[test]
+    fn glob_star() {
+        assert!(glob_match("DP-*", "DP-1"));
+        assert!(glob_match("DP-*", "DP-"));
+        assert!(!glob_match("eDP-*", "DP-1"));
+        assert!(glob_match("a*c", "abcbc"));
+        assert!(!glob_match("a*c", "abcb"));
+        assert!(glob_match("*-1", "HDMI-A-1"));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+        assert!(glob_match("DP-?", "DP-1"));
+        assert!(!glob_match("DP-?", "DP-"));
+        assert!(!glob_match("DP-?", "DP-10"));
+        assert!(glob_match("?", "é"));
+    }
+
+    #[test]
+    fn glob_empty_and_star_only() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "DP-1"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "DP-1"));
+        assert!(glob_match("**", "DP-1"));
+    }
+}
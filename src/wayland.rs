@@ -1,13 +1,15 @@
-use mlua::Function;
-
-use log::{debug, info};
+use log::{debug, error, info};
+use std::{
+    io::{Seek, Write},
+    os::fd::AsFd,
+};
 use uuid::Uuid;
 use wayland_client::{
     protocol::{
         wl_compositor, wl_output, wl_registry, wl_seat,
         wl_surface::{self},
     },
-    Connection, Dispatch, QueueHandle,
+    Connection, Dispatch, Proxy, QueueHandle, WEnum,
 };
 use wayland_protocols::{
     ext::idle_notify::v1::client::{ext_idle_notification_v1, ext_idle_notifier_v1},
@@ -17,51 +19,245 @@ use wayland_protocols::{
     },
     xdg::activation::v1::client::{xdg_activation_token_v1, xdg_activation_v1},
 };
-use wayland_protocols_wlr::gamma_control::v1::client::{
-    zwlr_gamma_control_manager_v1, zwlr_gamma_control_v1,
+use wayland_protocols_wlr::{
+    gamma_control::v1::client::{zwlr_gamma_control_manager_v1, zwlr_gamma_control_v1},
+    output_power_management::v1::client::{zwlr_output_power_manager_v1, zwlr_output_power_v1},
 };
 
-use crate::{color::Color, lua_init, types::State, INHIBIT_MANAGER, SURFACE};
+use crate::{
+    color::{self, Color},
+    types::{Event, LuaEnv, Request, State},
+    INHIBIT_MANAGER, SURFACE,
+};
+
+/// Current mode of an output
+#[derive(Clone, Copy, Debug)]
+pub struct Mode {
+    pub width: i32,
+    pub height: i32,
+    /// Refresh rate in mHz
+    pub refresh: i32,
+}
 
 #[derive(Debug)]
 pub struct Output {
     reg_name: u32,
     wl_output: wl_output::WlOutput,
-    name: Option<String>,
+    /// Connector name like `DP-1`, sent from version 4 on
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub make: String,
+    pub model: String,
+    pub mode: Option<Mode>,
+    pub scale: i32,
+    /// Whether `output_added` was sent, once the compositor sent all properties
+    announced: bool,
+    /// Whether the output is powered on, as reported by the power control
+    pub powered: Option<bool>,
+    power_control: Option<zwlr_output_power_v1::ZwlrOutputPowerV1>,
     color: Color,
     ramp_size: usize,
     color_changed: bool,
+    gamma_control: Option<zwlr_gamma_control_v1::ZwlrGammaControlV1>,
+}
+
+impl Output {
+    /// Name of the output for Lua, the registry name if the compositor doesn't send one
+    pub fn label(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| self.reg_name.to_string())
+    }
+
+    /// Sets the color of the output. It's applied as soon as the compositor told the size of
+    /// the gamma ramps.
+    pub fn set_color(&mut self, color: Color) {
+        self.color = color;
+        self.color_changed = true;
+        if let Err(e) = self.apply_color() {
+            error!("Failed to set gamma of output {}: {}", self.reg_name, e);
+        }
+    }
+
+    /// Changes the color temperature only, used by the night light
+    pub fn set_temp(&mut self, temp: u16) {
+        if self.color.temp != temp {
+            self.set_color(Color { temp, ..self.color });
+        }
+    }
+
+    /// Turns the output on or off, false if the compositor doesn't support power controls
+    pub fn set_power(&self, on: bool) -> bool {
+        let Some(power_control) = &self.power_control else {
+            return false;
+        };
+        power_control.set_mode(if on {
+            zwlr_output_power_v1::Mode::On
+        } else {
+            zwlr_output_power_v1::Mode::Off
+        });
+        true
+    }
+
+    pub fn temp(&self) -> u16 {
+        self.color.temp
+    }
+
+    pub fn brightness(&self) -> f64 {
+        self.color.brightness
+    }
+
+    /// Changes the brightness only, used to fade the screen
+    pub fn set_brightness(&mut self, brightness: f64) {
+        if self.color.brightness != brightness {
+            self.set_color(Color {
+                brightness,
+                ..self.color
+            });
+        }
+    }
+
+    fn apply_color(&mut self) -> std::io::Result<()> {
+        let Some(gamma_control) = &self.gamma_control else {
+            return Ok(());
+        };
+        if !self.color_changed || self.ramp_size == 0 {
+            return Ok(());
+        }
+
+        // The compositor expects the red, green and blue ramps one after another
+        let mut ramps = vec![0u16; self.ramp_size * 3];
+        let (r, gb) = ramps.split_at_mut(self.ramp_size);
+        let (g, b) = gb.split_at_mut(self.ramp_size);
+        color::colorramp_fill(r, g, b, self.ramp_size, self.color);
+
+        let mut file = shmemfdrs2::create_shmem(c"gamma-ramps")?;
+        file.write_all(bytemuck::cast_slice(&ramps))?;
+        file.rewind()?;
+        gamma_control.set_gamma(file.as_fd());
+        self.color_changed = false;
+        debug!("Set gamma of output {} to {:?}", self.reg_name, self.color);
+        Ok(())
+    }
+}
+
+/// Gets the gamma control of an output, the ramp size is sent by the compositor afterwards
+fn get_gamma_control(
+    manager: &zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1,
+    output: &mut Output,
+    qh: &QueueHandle<State>,
+) {
+    if output.gamma_control.is_none() {
+        output.gamma_control =
+            Some(manager.get_gamma_control(&output.wl_output, qh, output.reg_name));
+    }
+}
+
+/// Highest wl_output version, from 4 on outputs have a name and description
+const OUTPUT_VERSION: u32 = 4;
+
+/// Gets the power control of an output, used to turn it off without compositor specific IPC
+fn get_power_control(
+    manager: &zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+    output: &mut Output,
+    qh: &QueueHandle<State>,
+) {
+    if output.power_control.is_none() {
+        output.power_control =
+            Some(manager.get_output_power(&output.wl_output, qh, output.reg_name));
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct NotificationContext {
     pub uuid: Uuid,
+    pub generation: u32,
+}
+
+/// Hands the Wayland globals to the command loop once everything the Lua API needs is bound
+fn lua_init(state: &State) {
+    if let (Some(wl_seat), Some(idle_notifier)) = (&state.wl_seat, &state.idle_notifier) {
+        let env = LuaEnv {
+            wl_seat: wl_seat.clone(),
+            idle_notifier: idle_notifier.clone(),
+            qh: state.qh.clone(),
+            tx: state.tx.clone(),
+            outputs: state.outputs.clone(),
+        };
+        let _ = state.tx.blocking_send(Request::LuaInit(env));
+    }
 }
 
-impl Dispatch<wl_output::WlOutput, ()> for State {
+/// Outputs carry their registry name
+impl Dispatch<wl_output::WlOutput, u32> for State {
     fn event(
-        _state: &mut Self,
+        state: &mut Self,
         _output: &wl_output::WlOutput,
         event: wl_output::Event,
-        _: &(),
+        reg_name: &u32,
         _: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
-        if let wl_output::Event::Geometry {
-            x,
-            y,
-            physical_width,
-            physical_height,
-            subpixel,
-            make,
-            model,
-            transform,
-        } = event
-        {
-            info!(
-                "Output geometry: x: {}, y: {}, physical_width: {}, physical_height: {}, subpixel: {:?}, make: {}, model: {}, transform: {:?}",
-                x, y, physical_width, physical_height, subpixel, make, model, transform
-            );
+        let added = {
+            let mut outputs = state.outputs.lock().unwrap();
+            let Some(output) = outputs.get_mut(reg_name) else {
+                return;
+            };
+            // All properties are known after the first `done`
+            let done = matches!(event, wl_output::Event::Done);
+            match event {
+                wl_output::Event::Geometry {
+                    x,
+                    y,
+                    physical_width,
+                    physical_height,
+                    subpixel,
+                    make,
+                    model,
+                    transform,
+                } => {
+                    info!(
+                        "Output geometry: x: {}, y: {}, physical_width: {}, physical_height: {}, subpixel: {:?}, make: {}, model: {}, transform: {:?}",
+                        x, y, physical_width, physical_height, subpixel, make, model, transform
+                    );
+                    output.make = make;
+                    output.model = model;
+                }
+                wl_output::Event::Mode {
+                    flags: WEnum::Value(flags),
+                    width,
+                    height,
+                    refresh,
+                } if flags.contains(wl_output::Mode::Current) => {
+                    output.mode = Some(Mode {
+                        width,
+                        height,
+                        refresh,
+                    });
+                }
+                wl_output::Event::Scale { factor } => {
+                    output.scale = factor;
+                }
+                wl_output::Event::Name { name } => {
+                    output.name = Some(name);
+                }
+                wl_output::Event::Description { description } => {
+                    output.description = Some(description);
+                }
+                wl_output::Event::Done => {
+                    debug!("Output {}: {:?}", reg_name, output);
+                }
+                _ => {}
+            }
+            let added = done && !output.announced;
+            output.announced |= added;
+            added.then(|| output.label())
+        };
+        if let Some(name) = added {
+            info!("Output added: {}", name);
+            let _ = state
+                .tx
+                .blocking_send(Request::Event(Event::OutputAdded(name)));
         }
     }
 }
@@ -75,18 +271,17 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
         _: &Connection,
         qh: &QueueHandle<Self>,
     ) {
-        if let wl_registry::Event::Global {
-            name, interface, ..
-        } = event
-        {
-            match &interface[..] {
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } => match &interface[..] {
                 "wl_seat" => {
                     let wl_seat = registry.bind::<wl_seat::WlSeat, _, _>(name, 1, qh, ());
                     state.wl_seat = Some(wl_seat.clone());
                     debug!("wl_seat: {:?}", name);
-                    if state.wl_seat.is_some() && state.idle_notifier.is_some() {
-                        let _ = lua_init(state);
-                    }
+                    lua_init(state);
                 }
                 "ext_idle_notifier_v1" => {
                     let idle_notifier = registry
@@ -94,9 +289,7 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
 
                     debug!("ext_idle_notifier_v1: {:?}", name);
                     state.idle_notifier = Some(idle_notifier);
-                    if state.wl_seat.is_some() && state.idle_notifier.is_some() {
-                        let _ = lua_init(state);
-                    }
+                    lua_init(state);
                 }
                 "xdg_activation_v1" => {
                     let _activation =
@@ -139,7 +332,7 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
                     //state.gamma_control = Some(_gamma_control);
                 }
                 "zwlr_gamma_control_manager_v1" => {
-                    let _gamma_control_manager =
+                    let gamma_control_manager =
                         registry
                             .bind::<zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1, _, _>(
                                 name,
@@ -148,6 +341,24 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
                                 (),
                             );
                     info!("zwlr_gamma_control_manager_v1: {:?}", name);
+                    for output in state.outputs.lock().unwrap().values_mut() {
+                        get_gamma_control(&gamma_control_manager, output, qh);
+                    }
+                    state.gamma_manager = Some(gamma_control_manager);
+                }
+                "zwlr_output_power_manager_v1" => {
+                    let power_manager = registry
+                        .bind::<zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1, _, _>(
+                        name,
+                        1,
+                        qh,
+                        (),
+                    );
+                    info!("zwlr_output_power_manager_v1: {:?}", name);
+                    for output in state.outputs.lock().unwrap().values_mut() {
+                        get_power_control(&power_manager, output, qh);
+                    }
+                    state.power_manager = Some(power_manager);
                 }
                 "wl_compositor" => {
                     let compositor =
@@ -158,20 +369,61 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
                     *SURFACE.lock().unwrap() = Some(surface);
                 }
                 "wl_output" => {
-                    let wl_output = registry.bind::<wl_output::WlOutput, _, _>(name, 1, qh, ());
-                    let output = Output {
+                    let wl_output = registry.bind::<wl_output::WlOutput, _, _>(
+                        name,
+                        version.min(OUTPUT_VERSION),
+                        qh,
+                        name,
+                    );
+                    let mut output = Output {
                         reg_name: name,
                         wl_output,
                         name: None,
+                        description: None,
+                        make: String::new(),
+                        model: String::new(),
+                        mode: None,
+                        scale: 1,
+                        announced: false,
+                        powered: None,
+                        power_control: None,
                         color: Color::default(),
                         ramp_size: 0,
                         color_changed: false,
+                        gamma_control: None,
                     };
-                    state.outputs.insert(name, output);
+                    if let Some(manager) = &state.gamma_manager {
+                        get_gamma_control(manager, &mut output, qh);
+                    }
+                    if let Some(manager) = &state.power_manager {
+                        get_power_control(manager, &mut output, qh);
+                    }
+                    state.outputs.lock().unwrap().insert(name, output);
                     info!("wl_output: {:?}", name);
                 }
                 _ => {}
+            },
+            wl_registry::Event::GlobalRemove { name } => {
+                let Some(output) = state.outputs.lock().unwrap().remove(&name) else {
+                    return;
+                };
+                if let Some(gamma_control) = &output.gamma_control {
+                    gamma_control.destroy();
+                }
+                if let Some(power_control) = &output.power_control {
+                    power_control.destroy();
+                }
+                if output.wl_output.version() >= 3 {
+                    output.wl_output.release();
+                }
+                info!("Output removed: {}", output.label());
+                if output.announced {
+                    let _ = state
+                        .tx
+                        .blocking_send(Request::Event(Event::OutputRemoved(output.label())));
+                }
             }
+            _ => {}
         }
     }
 }
@@ -227,6 +479,84 @@ impl Dispatch<zwlr_gamma_control_v1::ZwlrGammaControlV1, ()> for State {
     }
 }
 
+/// Gamma controls carry the registry name of their output
+impl Dispatch<zwlr_gamma_control_v1::ZwlrGammaControlV1, u32> for State {
+    fn event(
+        state: &mut Self,
+        gamma_control: &zwlr_gamma_control_v1::ZwlrGammaControlV1,
+        event: zwlr_gamma_control_v1::Event,
+        reg_name: &u32,
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let mut outputs = state.outputs.lock().unwrap();
+        let Some(output) = outputs.get_mut(reg_name) else {
+            return;
+        };
+        match event {
+            zwlr_gamma_control_v1::Event::GammaSize { size } => {
+                debug!("Gamma ramp size of output {}: {}", reg_name, size);
+                output.ramp_size = size as usize;
+                if let Err(e) = output.apply_color() {
+                    error!("Failed to set gamma of output {}: {}", reg_name, e);
+                }
+            }
+            zwlr_gamma_control_v1::Event::Failed => {
+                // Another client controls the gamma of this output or it doesn't support it
+                error!("Gamma control of output {} failed", reg_name);
+                gamma_control.destroy();
+                output.gamma_control = None;
+                output.ramp_size = 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Power controls carry the registry name of their output
+impl Dispatch<zwlr_output_power_v1::ZwlrOutputPowerV1, u32> for State {
+    fn event(
+        state: &mut Self,
+        power_control: &zwlr_output_power_v1::ZwlrOutputPowerV1,
+        event: zwlr_output_power_v1::Event,
+        reg_name: &u32,
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let mut outputs = state.outputs.lock().unwrap();
+        let Some(output) = outputs.get_mut(reg_name) else {
+            return;
+        };
+        match event {
+            zwlr_output_power_v1::Event::Mode { mode } => {
+                let powered = matches!(mode, WEnum::Value(zwlr_output_power_v1::Mode::On));
+                debug!("Output {} powered on: {}", reg_name, powered);
+                output.powered = Some(powered);
+            }
+            zwlr_output_power_v1::Event::Failed => {
+                // The output was disconnected or another client controls its power
+                error!("Power control of output {} failed", reg_name);
+                power_control.destroy();
+                output.power_control = None;
+                output.powered = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+        _event: zwlr_output_power_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
 impl Dispatch<zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1, ()> for State {
     fn event(
         _: &mut Self,
@@ -287,16 +617,15 @@ impl Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, NotificationConte
         _qh: &QueueHandle<Self>,
     ) {
         debug!("Idle Notification: {:?} {:?}", event, ctx.uuid);
-        let map = state.notification_list.lock().unwrap();
-        let binding = state.lua.lock().unwrap();
-        let globals = binding.globals();
-        let fn_name = map.get(&ctx.uuid).unwrap().0.clone();
-        let handler: Function = globals.get(fn_name).unwrap();
-        let _ = handler.call::<_, ()>(match event {
-            ext_idle_notification_v1::Event::Idled => "idled",
-            ext_idle_notification_v1::Event::Resumed => "resumed",
-            _ => "unknown",
-        });
+        // Lua callbacks are run by the command loop, dispatch only forwards the event
+        let request = match event {
+            ext_idle_notification_v1::Event::Idled => Request::Idled(ctx.uuid, ctx.generation),
+            ext_idle_notification_v1::Event::Resumed => Request::Resumed(ctx.uuid, ctx.generation),
+            _ => return,
+        };
+        if let Err(e) = state.tx.blocking_send(request) {
+            error!("Failed to forward idle notification: {}", e);
+        }
     }
 }
 